@@ -0,0 +1,39 @@
+use super::{check_response, reachable, read_json, transport_error, Backend, BackendError, BackendResponseBody};
+use crate::Error;
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// Posts the request and waits for the response carrying the output path.
+pub struct HttpBackend {
+    url: String,
+    http_client: reqwest::Client,
+    timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl HttpBackend {
+    pub fn new(url: String, http_client: reqwest::Client, timeout: Duration, read_timeout: Duration) -> Self {
+        HttpBackend { url, http_client, timeout, read_timeout }
+    }
+}
+
+#[async_trait]
+impl Backend for HttpBackend {
+    async fn run(&self, request: &serde_json::Value) -> Result<String, BackendError> {
+        let rsp = self.http_client.post(&self.url)
+            .json(request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(transport_error)?;
+
+        let rsp = check_response(rsp).await?;
+        let rsp_body: BackendResponseBody = read_json(rsp, self.read_timeout).await?;
+
+        Ok(rsp_body.output_path)
+    }
+
+    async fn health(&self) -> Result<(), Error> {
+        reachable(&self.http_client, &self.url).await
+    }
+}
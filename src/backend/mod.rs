@@ -0,0 +1,254 @@
+mod http;
+mod poll;
+mod subprocess;
+
+pub use self::http::HttpBackend;
+pub use self::poll::PollBackend;
+pub use self::subprocess::SubprocessBackend;
+
+use crate::{Error, config::Secret, schemas::Stage};
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+const HEALTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackendResponseBody {
+    pub output_path: String,
+}
+
+/// Error body returned by backends, e.g. `{"code": "...", "message": "..."}` or
+/// FastAPI's `{"detail": "..."}`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BackendErrorBody {
+    pub code: Option<String>,
+    #[serde(alias = "detail", alias = "error")]
+    pub message: Option<String>,
+}
+
+impl std::fmt::Display for BackendErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.code, &self.message) {
+            (Some(code), Some(message)) => write!(f, "{}: {}", code, message),
+            (Some(code), None) => write!(f, "{}", code),
+            (None, Some(message)) => write!(f, "{}", message),
+            (None, None) => write!(f, "no details"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// The backend is unreachable, overloaded or restarting; the task can be retried later.
+    Retryable(Error),
+    /// The backend failed in a way that retrying will not fix.
+    Permanent(Error),
+    /// The backend rejected the task's input.
+    InvalidInput(BackendErrorBody),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Retryable(e) => write!(f, "backend unavailable: {}", e),
+            BackendError::Permanent(e) => write!(f, "backend failed: {}", e),
+            BackendError::InvalidInput(body) => write!(f, "invalid input: {}", body),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// Classifies a backend response by status code, passing successful responses through.
+pub(crate) async fn check_response(rsp: reqwest::Response) -> Result<reqwest::Response, BackendError> {
+    let status = rsp.status();
+    if status.is_success() {
+        return Ok(rsp);
+    }
+
+    let text = rsp.text().await.unwrap_or_default();
+    let body = serde_json::from_str::<BackendErrorBody>(&text).unwrap_or_else(|_| BackendErrorBody {
+        code: None,
+        // Proxies answer with HTML pages; keep the log readable.
+        message: Some(text.chars().take(200).collect()),
+    });
+
+    match status.as_u16() {
+        408 | 429 | 502 | 503 | 504 => Err(BackendError::Retryable(format!("HTTP {}: {}", status, body).into())),
+        400 | 413 | 415 | 422 => Err(BackendError::InvalidInput(body)),
+        _ => Err(BackendError::Permanent(format!("HTTP {}: {}", status, body).into())),
+    }
+}
+
+/// Classifies a transport error: a timeout on an established request means a hung render,
+/// anything else an unreachable backend. Connect timeouts also count as timeouts in reqwest,
+/// so they are checked first.
+pub(crate) fn transport_error(e: reqwest::Error) -> BackendError {
+    if e.is_timeout() && !e.is_connect() {
+        BackendError::Permanent(e.into())
+    } else {
+        BackendError::Retryable(e.into())
+    }
+}
+
+/// Reads a successful response's JSON body, failing once the backend sent nothing for
+/// `read_timeout`. A stalled body would otherwise hold the worker until the request timeout.
+pub(crate) async fn read_json<T: DeserializeOwned>(
+    mut rsp: reqwest::Response,
+    read_timeout: Duration,
+) -> Result<T, BackendError> {
+    let mut body = Vec::new();
+    loop {
+        match tokio::time::timeout(read_timeout, rsp.chunk()).await {
+            Ok(Ok(Some(chunk))) => body.extend_from_slice(&chunk),
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(transport_error(e)),
+            Err(_) => return Err(BackendError::Retryable(
+                format!("response body stalled for {:?}", read_timeout).into()
+            )),
+        }
+    }
+    serde_json::from_slice(&body).map_err(|e| BackendError::Permanent(e.into()))
+}
+
+#[derive(Clone, Debug)]
+pub struct BackendOptions {
+    /// Upper bound for a single blocking request or command, including the whole render.
+    pub request_timeout: Duration,
+    /// Longest pause allowed while a response body is being received.
+    pub read_timeout: Duration,
+    /// How often job-based backends are asked for the status of a job.
+    pub poll_interval: Duration,
+    /// How long a job may run before it is cancelled.
+    pub poll_deadline: Duration,
+}
+
+fn default_stage() -> Stage {
+    Stage::Stylize
+}
+
+fn default_api_key_header() -> String {
+    "X-API-Key".to_owned()
+}
+
+/// How to reach one backend: its URL, the stage it serves, and its credentials.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BackendConfig {
+    pub url: String,
+    #[serde(default = "default_stage")]
+    pub stage: Stage,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token: Option<Secret>,
+    /// Sent in the `api_key_header` header.
+    pub api_key: Option<Secret>,
+    #[serde(default = "default_api_key_header")]
+    pub api_key_header: String,
+    /// PEM bundle of extra CA certificates to trust.
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS, used together with `client_key`.
+    pub client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Whether the backend trims, resizes and resamples the source itself from the request's
+    /// `start`, `end`, `max_width`, `max_height` and `fps` fields. Otherwise the worker does
+    /// that with ffmpeg first.
+    #[serde(default)]
+    pub native_preprocess: bool,
+}
+
+impl BackendConfig {
+    pub fn new(url: String, stage: Stage) -> Self {
+        BackendConfig {
+            url,
+            stage,
+            token: None,
+            api_key: None,
+            api_key_header: default_api_key_header(),
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            native_preprocess: false,
+        }
+    }
+
+    /// Builds an HTTP client that authenticates every request to this backend.
+    pub async fn http_client(&self, connect_timeout: Duration) -> Result<reqwest::Client, Error> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &self.token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        if let Some(api_key) = &self.api_key {
+            let mut value = HeaderValue::from_str(api_key.expose())?;
+            value.set_sensitive(true);
+            headers.insert(HeaderName::from_bytes(self.api_key_header.as_bytes())?, value);
+        }
+
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(connect_timeout)
+            .tcp_keepalive(Duration::from_secs(60));
+
+        if let Some(ca_cert) = &self.ca_cert {
+            let pem = tokio::fs::read_to_string(ca_cert).await?;
+            let end_marker = "-----END CERTIFICATE-----";
+            for block in pem.split_inclusive(end_marker).filter(|block| block.contains(end_marker)) {
+                builder = builder.add_root_certificate(reqwest::Certificate::from_pem(block.as_bytes())?);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let cert = tokio::fs::read(client_cert).await?;
+                let key = tokio::fs::read(client_key).await?;
+                builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&cert, &key)?);
+            },
+            (None, None) => {},
+            _ => return Err(format!("Backend {} needs both a client certificate and key", self.url).into()),
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// A model server that turns a JSON request into an output video path.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn run(&self, request: &serde_json::Value) -> Result<String, BackendError>;
+
+    /// Readiness check of the backend. Backends that cannot be probed are always ready.
+    async fn health(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Readiness check of an HTTP backend. Only reachability is tested, so any response counts.
+pub(crate) async fn reachable(http_client: &reqwest::Client, url: &str) -> Result<(), Error> {
+    http_client.get(url).timeout(HEALTH_REQUEST_TIMEOUT).send().await?;
+    Ok(())
+}
+
+/// Picks the protocol adapter for a `--backend` entry by its URL scheme:
+///
+/// - `http://…`, `https://…`: blocking JSON POST returning the output path.
+/// - `poll+http://…`, `poll+https://…`: submit a job, then poll its status.
+/// - `exec:<command> <args…>`: run a local command with `{field}` placeholders
+///   filled in from the request.
+pub fn from_url(
+    url: &str,
+    http_client: reqwest::Client,
+    options: &BackendOptions,
+) -> Result<Arc<dyn Backend>, Error> {
+    if let Some(url) = url.strip_prefix("poll+") {
+        Ok(Arc::new(PollBackend::new(url.to_owned(), http_client, options)))
+    } else if let Some(command) = url.strip_prefix("exec:") {
+        Ok(Arc::new(SubprocessBackend::parse(command, options.request_timeout)?))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Arc::new(HttpBackend::new(url.to_owned(), http_client, options.request_timeout, options.read_timeout)))
+    } else {
+        Err(format!("Unsupported backend URL: {}", url).into())
+    }
+}
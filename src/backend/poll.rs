@@ -0,0 +1,125 @@
+use super::{check_response, reachable, read_json, transport_error, Backend, BackendError, BackendOptions};
+use crate::Error;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const STATUS_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobSubmitResponseBody {
+    pub job_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobStatusResponseBody {
+    pub status: String,
+    pub output_path: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Submits the request as a job with `POST {url}`, then polls `GET {url}/{job_id}`
+/// until the job is `completed` or `failed`. Jobs still running after the deadline, or whose
+/// status can't be read, are cancelled with `DELETE {url}/{job_id}`.
+pub struct PollBackend {
+    url: String,
+    http_client: reqwest::Client,
+    poll_interval: Duration,
+    deadline: Duration,
+    read_timeout: Duration,
+}
+
+impl PollBackend {
+    pub fn new(url: String, http_client: reqwest::Client, options: &BackendOptions) -> Self {
+        PollBackend {
+            url,
+            http_client,
+            poll_interval: options.poll_interval,
+            deadline: options.poll_deadline,
+            read_timeout: options.read_timeout,
+        }
+    }
+
+    async fn cancel(&self, status_url: &str) {
+        let rsp = self.http_client.delete(status_url)
+            .timeout(STATUS_REQUEST_TIMEOUT)
+            .send()
+            .await;
+        if let Err(e) = rsp {
+            tracing::warn!(status_url, error = ?e, "Failed to cancel job");
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for PollBackend {
+    async fn run(&self, request: &serde_json::Value) -> Result<String, BackendError> {
+        let rsp = self.http_client.post(&self.url)
+            .json(request)
+            .timeout(STATUS_REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(transport_error)?;
+
+        let rsp = check_response(rsp).await?;
+        let job: JobSubmitResponseBody = read_json(rsp, self.read_timeout).await?;
+
+        let status_url = format!("{}/{}", self.url.trim_end_matches('/'), job.job_id);
+        let deadline = Instant::now() + self.deadline;
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+
+            if Instant::now() >= deadline {
+                self.cancel(&status_url).await;
+                return Err(BackendError::Permanent(
+                    format!("Job {} did not finish within {:?}", job.job_id, self.deadline).into()
+                ));
+            }
+
+            // Unlike a render, a slow status answer only delays the next poll, so the timeout
+            // covers the whole exchange and counts as a dropped poll.
+            let status = tokio::time::timeout(STATUS_REQUEST_TIMEOUT, async {
+                let rsp = self.http_client.get(&status_url)
+                    .send()
+                    .await
+                    .map_err(transport_error)?;
+                let rsp = check_response(rsp).await?;
+                read_json::<JobStatusResponseBody>(rsp, self.read_timeout).await
+            }).await.unwrap_or_else(|_| Err(BackendError::Retryable(
+                format!("status request took longer than {:?}", STATUS_REQUEST_TIMEOUT).into()
+            )));
+
+            let status = match status {
+                Ok(status) => status,
+                Err(BackendError::Retryable(e)) => {
+                    // The job keeps running on the backend; a dropped poll is not fatal.
+                    tracing::warn!(job_id = %job.job_id, error = ?e, "Failed to poll job");
+                    continue;
+                },
+                Err(e) => {
+                    // Nobody will collect the job's output, so stop it from using the backend.
+                    self.cancel(&status_url).await;
+                    return Err(e);
+                },
+            };
+
+            match status.status.as_str() {
+                "completed" => {
+                    return status.output_path.ok_or_else(|| {
+                        BackendError::Permanent(format!("Job {} completed without an output path", job.job_id).into())
+                    });
+                },
+                "failed" => {
+                    let error = status.error.unwrap_or_else(|| "unknown error".to_owned());
+                    return Err(BackendError::Permanent(format!("Job {} failed: {}", job.job_id, error).into()));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    async fn health(&self) -> Result<(), Error> {
+        reachable(&self.http_client, &self.url).await
+    }
+}
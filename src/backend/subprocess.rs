@@ -0,0 +1,95 @@
+use super::{Backend, BackendError, BackendErrorBody, BackendResponseBody};
+use crate::Error;
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::process::Command;
+
+const EX_DATAERR: i32 = 65;
+const EX_TEMPFAIL: i32 = 75;
+
+/// Runs a local command per task.
+///
+/// `{field}` placeholders in the arguments are replaced with top-level fields of the request,
+/// and every field is also exported as an `OMNI_<FIELD>` environment variable. The command
+/// prints either a JSON `{"output_path": …}` object or the bare output path as its last line.
+/// Following `sysexits.h`, exit code 65 (`EX_DATAERR`) marks invalid input, optionally with a
+/// JSON error body on stderr, and 75 (`EX_TEMPFAIL`) a temporary failure worth retrying.
+pub struct SubprocessBackend {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl SubprocessBackend {
+    pub fn parse(command: &str, timeout: Duration) -> Result<Self, Error> {
+        let mut parts = command.split_whitespace().map(str::to_owned);
+        let program = parts.next().ok_or("Empty backend command")?;
+
+        Ok(SubprocessBackend {
+            program,
+            args: parts.collect(),
+            timeout,
+        })
+    }
+}
+
+fn field_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+#[async_trait]
+impl Backend for SubprocessBackend {
+    async fn run(&self, request: &serde_json::Value) -> Result<String, BackendError> {
+        let fields = request.as_object().cloned().unwrap_or_default();
+
+        let args = self.args.iter().map(|arg| {
+            fields.iter().fold(arg.clone(), |arg, (key, value)| {
+                arg.replace(&format!("{{{}}}", key), &field_to_string(value))
+            })
+        });
+
+        let envs = fields.iter().map(|(key, value)| {
+            (format!("OMNI_{}", key.to_uppercase()), field_to_string(value))
+        });
+
+        let output = Command::new(&self.program)
+            .args(args)
+            .envs(envs)
+            .kill_on_drop(true)
+            .output();
+
+        // Dropping the future on timeout kills the child process.
+        let output = tokio::time::timeout(self.timeout, output)
+            .await
+            .map_err(|_| BackendError::Permanent(format!("{} did not finish within {:?}", self.program, self.timeout).into()))?
+            .map_err(|e| BackendError::Retryable(e.into()))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let error = format!("{} exited with {}: {}", self.program, output.status, stderr.trim());
+            return Err(match output.status.code() {
+                Some(EX_DATAERR) => BackendError::InvalidInput(
+                    serde_json::from_str(stderr.trim()).unwrap_or_else(|_| BackendErrorBody {
+                        code: None,
+                        message: Some(stderr.trim().to_owned()),
+                    })
+                ),
+                Some(EX_TEMPFAIL) => BackendError::Retryable(error.into()),
+                _ => BackendError::Permanent(error.into()),
+            });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let last_line = stdout.lines().map(str::trim).rfind(|line| !line.is_empty())
+            .ok_or_else(|| BackendError::Permanent(format!("{} printed no output path", self.program).into()))?;
+
+        match serde_json::from_str::<BackendResponseBody>(last_line) {
+            Ok(rsp_body) => Ok(rsp_body.output_path),
+            Err(_) => Ok(last_line.to_owned()),
+        }
+    }
+}
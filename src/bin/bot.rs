@@ -1,59 +1,833 @@
-use mongodb::bson::doc;
-use omni_bot_rs::{UserData, Error, amqp, commands, db, schemas};
-use futures::StreamExt;
-use lapin::{options::{BasicConsumeOptions, BasicAckOptions}, types::FieldTable};
-use poise::serenity_prelude::{self as serenity, ChannelId, Channel, AttachmentType};
-use tokio::sync::mpsc;
-use std::{env, sync::Arc, time::Duration};
+use clap::Parser;
+use mongodb::{bson::{self, doc, oid::ObjectId, DateTime, Document}, options::{FindOneAndUpdateOptions, ReturnDocument}, Collection};
+use omni_bot_rs::{UserData, Error, amqp::{self, QueueNames}, commands::{self, video_to_video::stage_available}, config::{self, BotSettings, Secret}, db, embeds, error_code::ErrorCode, ffmpeg, health::{self, Health}, metrics, outbox, schemas::{self, Stage}, shutdown::{self, InFlight}, telemetry::{self, LogConfig}};
+use futures::{StreamExt, TryStreamExt};
+use lapin::{options::{BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions, QueueDeclareOptions}, types::FieldTable};
+use poise::serenity_prelude::{self as serenity, gateway::ConnectionStage, ButtonStyle, ChannelId, AttachmentType, CreateMessage, GuildId, Message, MessageId, UserId};
+use tokio::{sync::{mpsc, watch}, time::MissedTickBehavior};
+use tracing::Instrument;
+use serde::Deserialize;
+use std::{collections::HashMap, future::Future, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
 
 async fn on_error(error: poise::FrameworkError<'_, UserData, Error>) {
     match error {
         poise::FrameworkError::Setup { error, .. } => {
-            eprintln!("Failed to setup bot: {:?}", error);
+            tracing::error!(?error, "Failed to setup bot");
         }
         poise::FrameworkError::Command { error, ctx, .. } => {
-            eprintln!("Error in command `{}`: {:?}", ctx.command().name, error);
+            tracing::error!(command = ctx.command().name, ?error, "Error in command");
         }
         error => {
             if let Err(e) = poise::builtins::on_error(error).await {
-                eprintln!("Error in error handler: {:?}", e);
+                tracing::error!(error = ?e, "Error in error handler");
             }
         }
     }
 }
 
+async fn notify_group(
+    ctx: &serenity::Context,
+    task_collection: &Collection<schemas::VideoStylizerTaskInDB>,
+    group_collection: &Collection<schemas::VideoStylizerGroupInDB>,
+    preference_collection: &Collection<schemas::UserPreferencesInDB>,
+    group_id: &str,
+    ffmpeg_path: &str,
+) -> Result<(), Error> {
+    let group_id = ObjectId::parse_str(group_id)?;
+    let group = match group_collection.find_one(doc! {"_id": group_id}, None).await? {
+        Some(group) if group.status == "pending" => group,
+        _ => return Ok(()),
+    };
+
+    let mut tasks = Vec::with_capacity(group.task_ids.len());
+    for task_id in &group.task_ids {
+        let task = task_collection.find_one(doc! {"_id": ObjectId::parse_str(task_id)?}, None).await?;
+        match task {
+            Some(task) if task.status != "pending" && task.status != "processing" => {
+                tasks.push((task_id, task))
+            },
+            _ => return Ok(()),
+        }
+    }
+
+    // Every member is done; claim the group so its reply is only posted once.
+    let claimed = group_collection.find_one_and_update(
+        doc! {"_id": group_id, "status": "pending"},
+        doc! {"$set": {"status": "completed", "updated_at": DateTime::now()}},
+        FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+    ).await?;
+    let Some(group) = claimed else {
+        return Ok(());
+    };
+
+    let mut dst_paths = Vec::with_capacity(tasks.len() + 1);
+    for (task_id, task) in &tasks {
+        if let ("completed", Some(result)) = (task.status.as_str(), &task.result) {
+            if tokio::fs::try_exists(result).await? {
+                dst_paths.push((result.clone(), format!("{}.{}", task_id, task.output_format.extension())));
+            }
+        }
+    }
+
+    if group.grid && dst_paths.len() > 1 {
+        let inputs: Vec<_> = dst_paths.iter().map(|(path, _)| path.clone()).collect();
+        let grid_name = format!("{}_grid.mp4", group_id.to_hex());
+        let grid_path = Path::new(&inputs[0]).with_file_name(&grid_name);
+        match ffmpeg::hstack(ffmpeg_path, &inputs, &grid_path, 480).await {
+            Ok(()) => dst_paths.insert(0, (grid_path.to_string_lossy().into_owned(), grid_name)),
+            Err(e) => tracing::warn!(error = ?e, "Failed to render comparison grid"),
+        }
+    }
+
+    // Every member of a group stylizes the same source video.
+    let source_thumbnail = match dst_paths.first() {
+        Some(_) => embed_thumbnail(tasks[0].1.source_thumbnail.as_ref()).await,
+        None => None,
+    };
+    let mut attachments = dst_paths.clone();
+    attachments.extend(source_thumbnail.clone());
+
+    let author = UserId(group.user_id).to_user(ctx).await.ok();
+    let members: Vec<_> = tasks.iter().map(|(task_id, task)| (task_id.as_str(), task)).collect();
+    let channels = notification_channels(ctx, preference_collection, group.channel_id, group.user_id).await;
+    let placeholder = group.placeholder_message_id.map(|id| (ChannelId(group.channel_id), MessageId(id)));
+    let posted = post_to_all(&channels, "group", |channel| {
+        let (attachments, group, members, author, source_thumbnail) =
+            (&attachments, &group, &members, &author, &source_thumbnail);
+        async move {
+            let dst_files = open_files(attachments).await?;
+            let message = channel.send_files(
+                ctx,
+                dst_files.iter().map(|(file, filename)| AttachmentType::File {
+                    file,
+                    filename: filename.clone(),
+                }),
+                |m| {
+                    m.content(format!("<@{}>", group.user_id)).embed(|e| {
+                        embeds::group(e, &group_id.to_hex(), group, members);
+                        if let Some(author) = author {
+                            embeds::author(e, author);
+                        }
+                        if let Some((_, name)) = source_thumbnail {
+                            e.thumbnail(format!("attachment://{}", name));
+                        }
+                        e
+                    });
+                    reply_to_placeholder(m, channel, placeholder)
+                },
+            ).await?;
+            Ok(message)
+        }
+    }).await;
+
+    let message = match posted {
+        Ok(message) => message,
+        Err(e) => {
+            // Hand the group back to the reaper, which only retries pending groups.
+            group_collection.update_one(
+                doc! {"_id": group_id, "status": "completed"},
+                doc! {"$set": {"status": "pending"}},
+                None,
+            ).await?;
+            return Err(e);
+        },
+    };
+
+    let status = format!("> Your videos are ready. Group ID: **{}.**", group_id.to_hex());
+    update_placeholder(ctx, placeholder, &message, tasks[0].1.guild_id, status).await;
+
+    Ok(())
+}
+
+/// Channels a notification for `user_id` is posted to: the one the command was used in, which
+/// may be a guild channel, a thread, a forum post or a DM, then the user's DMs if they opted in.
+async fn notification_channels(
+    ctx: &serenity::Context,
+    preference_collection: &Collection<schemas::UserPreferencesInDB>,
+    channel_id: u64,
+    user_id: u64,
+) -> Vec<ChannelId> {
+    let mut channels = vec![ChannelId(channel_id)];
+    let preferences = preference_collection.find_one(doc! {"user_id": user_id as i64}, None).await;
+    match preferences {
+        Ok(Some(preferences)) if preferences.notify_by_dm => {
+            match UserId(user_id).create_dm_channel(ctx).await {
+                Ok(dm) if dm.id != channels[0] => channels.push(dm.id),
+                Ok(_) => {},
+                Err(e) => {
+                    tracing::warn!(error = ?e, "Failed to open DM channel");
+                    metrics::DISCORD_SEND_FAILURES.with_label_values(&["dm"]).inc();
+                },
+            }
+        },
+        Ok(_) => {},
+        Err(e) => tracing::warn!(error = ?e, "Failed to read user preferences"),
+    }
+    channels
+}
+
+/// Makes `m` a reply to the placeholder posted when the task was created, if `m` goes to the
+/// same channel. The message is still sent if the placeholder was deleted.
+fn reply_to_placeholder<'a, 'b>(
+    m: &'b mut CreateMessage<'a>,
+    channel: ChannelId,
+    placeholder: Option<(ChannelId, MessageId)>,
+) -> &'b mut CreateMessage<'a> {
+    if let Some((placeholder_channel, placeholder_id)) = placeholder {
+        if placeholder_channel == channel {
+            m.0.insert("message_reference", serde_json::json!({
+                "message_id": placeholder_id.0.to_string(),
+                "channel_id": channel.0.to_string(),
+                "fail_if_not_exists": false,
+            }));
+        }
+    }
+    m
+}
+
+/// Replaces the "We are working on your video" placeholder with `status` and a link to the
+/// posted notification, so the request and its result can be matched from either side.
+async fn update_placeholder(
+    ctx: &serenity::Context,
+    placeholder: Option<(ChannelId, MessageId)>,
+    notification: &Message,
+    guild_id: Option<u64>,
+    status: String,
+) {
+    let Some((channel, placeholder_id)) = placeholder else {
+        return;
+    };
+    let link = notification.id.link(notification.channel_id, guild_id.map(GuildId));
+    let edited = channel.edit_message(ctx, placeholder_id, |m| {
+        m.content(format!("{} [Jump to the result]({})", status, link))
+    }).await;
+    if let Err(e) = edited {
+        tracing::warn!(error = ?e, "Failed to update placeholder message");
+    }
+}
+
+/// Posts to each channel in turn, returning the first message that went through. A
+/// notification counts as delivered if any of the channels got it.
+async fn post_to_all<F, Fut>(channels: &[ChannelId], kind: &str, mut post: F) -> Result<Message, Error>
+where
+    F: FnMut(ChannelId) -> Fut,
+    Fut: Future<Output = Result<Message, Error>>,
+{
+    let mut posted = None;
+    let mut last_error = None;
+    for &channel in channels {
+        match post(channel).await {
+            Ok(message) => {
+                posted.get_or_insert(message);
+            },
+            Err(e) => {
+                tracing::warn!(channel_id = channel.0, error = ?e, "Failed to post notification");
+                metrics::DISCORD_SEND_FAILURES.with_label_values(&[kind]).inc();
+                last_error = Some(e);
+            },
+        }
+    }
+    posted.ok_or_else(|| last_error.unwrap_or_else(|| "no channel to post to".into()))
+}
+
+/// Returns the path and attachment name of a thumbnail the worker rendered for an embed.
+/// Embeds still go out without it, so a missing file is skipped.
+async fn embed_thumbnail(path: Option<&String>) -> Option<(String, String)> {
+    let path = path?;
+    if !tokio::fs::try_exists(path).await.unwrap_or(false) {
+        tracing::warn!(?path, "Thumbnail file does not exist");
+        return None;
+    }
+    let name = Path::new(path).file_name()?.to_string_lossy().into_owned();
+    Some((path.clone(), name))
+}
+
+/// Opens result files for one message; each message needs its own handles to read from.
+async fn open_files(paths: &[(String, String)]) -> Result<Vec<(tokio::fs::File, String)>, Error> {
+    let mut files = Vec::with_capacity(paths.len());
+    for (path, name) in paths {
+        files.push((tokio::fs::File::open(path).await?, name.clone()));
+    }
+    Ok(files)
+}
+
+/// Records a task update published by a worker and notifies the user once the task is done.
+///
+/// Only the first final update of a task is applied, and it claims the notification by setting
+/// `notified_at` in the same write. A completion redelivered after the bot crashed is skipped
+/// instead of rolling the task back or posting its result twice, so a completion that could not
+/// be recorded is safe to return to the queue.
+async fn handle_completion(
+    ctx: &serenity::Context,
+    task: schemas::VideoStylizerTaskInQueue,
+    task_collection: &Collection<schemas::VideoStylizerTaskInDB>,
+    group_collection: &Collection<schemas::VideoStylizerGroupInDB>,
+    preference_collection: &Collection<schemas::UserPreferencesInDB>,
+    settings: &BotSettings,
+    ffmpeg_path: &str,
+) -> Result<(), Error> {
+    tracing::info!(?task, "Got task update");
+    let Ok(task_id) = ObjectId::parse_str(&task.task_id) else {
+        tracing::error!("Invalid task ID");
+        return Ok(());
+    };
+
+    let finished = task.status == "completed" || task.status == "failed";
+    let mut update = doc! {
+        "status": task.status.clone(),
+        "result": task.result.clone(),
+        "preview_frames": task.preview_frames.clone(),
+        "artifacts": mongodb::bson::to_bson(&task.artifacts).unwrap(),
+        "audio": mongodb::bson::to_bson(&task.audio).unwrap(),
+        "source_thumbnail": task.source_thumbnail.clone(),
+        "result_thumbnail": task.result_thumbnail.clone(),
+        "error": mongodb::bson::to_bson(&task.error).unwrap(),
+        "updated_at": DateTime::now(),
+    };
+    if finished {
+        update.insert("notified_at", DateTime::now());
+    }
+    let updated = task_collection.find_one_and_update(
+        doc! {"_id": task_id, "status": {"$nin": ["completed", "failed"]}},
+        doc! {"$set": update},
+        FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+    ).await?;
+    let Some(updated) = updated else {
+        tracing::info!("Task already finished, skipping update");
+        return Ok(());
+    };
+
+    if finished {
+        metrics::TASKS_FINISHED
+            .with_label_values(&[&task.status, metrics::style_label(task.style.as_deref()), &metrics::guild_label(task.guild_id)])
+            .inc();
+    }
+
+    if let Some(group_id) = &task.group_id {
+        if let Err(e) = notify_group(ctx, task_collection, group_collection, preference_collection, group_id, ffmpeg_path).await {
+            tracing::error!(group_id, error = ?e, "Failed to notify group");
+        }
+        return Ok(());
+    }
+
+    match task.status.as_str() {
+        // An intermediate stage finished; its artifact is recorded above.
+        "processing" => {},
+        "completed" | "failed" => {
+            let notified = notify_task(ctx, preference_collection, settings, &task.task_id, &updated).await;
+            if let Err(e) = record_notification(task_collection, task_id, notified).await {
+                tracing::error!(error = ?e, "Failed to record notification");
+            }
+        },
+        _ => {
+            tracing::error!("Invalid task status");
+        }
+    }
+    Ok(())
+}
+
+/// Stores the message a task's result was posted in, or releases the claim on the notification
+/// if it could not be posted, so it can be retried.
+async fn record_notification(
+    task_collection: &Collection<schemas::VideoStylizerTaskInDB>,
+    task_id: ObjectId,
+    notified: Result<MessageId, Error>,
+) -> Result<(), Error> {
+    let update = match notified {
+        Ok(message_id) => doc! {"$set": {"notification_message_id": message_id.0 as i64}},
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to notify user");
+            doc! {"$set": {"notified_at": null}}
+        },
+    };
+    task_collection.update_one(doc! {"_id": task_id}, update, None).await?;
+    Ok(())
+}
+
+/// Posts the result or failure of a finished task where the user asked to be notified.
+async fn notify_task(
+    ctx: &serenity::Context,
+    preference_collection: &Collection<schemas::UserPreferencesInDB>,
+    settings: &BotSettings,
+    task_id: &str,
+    task: &schemas::VideoStylizerTaskInDB,
+) -> Result<MessageId, Error> {
+    let channels = notification_channels(ctx, preference_collection, task.channel_id, task.user_id).await;
+    let placeholder = task.placeholder_message_id.map(|id| (ChannelId(task.channel_id), MessageId(id)));
+    let author = UserId(task.user_id).to_user(ctx).await.ok();
+
+    if task.status == "failed" {
+        let message = post_to_all(&channels, "failure", |channel| {
+            let author = &author;
+            async move {
+                let message = channel.send_message(ctx, |m| {
+                    m.content(format!("<@{}>", task.user_id)).embed(|e| {
+                        embeds::task(e, task_id, task);
+                        if let Some(author) = author {
+                            embeds::author(e, author);
+                        }
+                        e
+                    });
+                    reply_to_placeholder(m, channel, placeholder)
+                }).await?;
+                Ok(message)
+            }
+        }).await?;
+        let status = format!("> Your video could not be stylized. Task ID: **{}.**", task_id);
+        update_placeholder(ctx, placeholder, &message, task.guild_id, status).await;
+        return Ok(message.id);
+    }
+
+    let Some(result) = &task.result else {
+        return Err("completed task has no result".into());
+    };
+
+    let dst_paths = if task.preview {
+        task.preview_frames.iter().map(|frame| {
+            (frame.clone(), frame.split('/').next_back().unwrap().to_owned())
+        }).collect()
+    } else {
+        vec![(
+            result.clone(),
+            format!("{}.{}", task_id, task.output_format.extension()),
+        )]
+    };
+
+    let mut existing_paths = Vec::with_capacity(dst_paths.len());
+    for (dst_path, dst_name) in dst_paths {
+        if !tokio::fs::try_exists(&dst_path).await? {
+            tracing::warn!(?dst_path, "Result file does not exist");
+            continue;
+        }
+
+        existing_paths.push((dst_path, dst_name));
+    }
+
+    if existing_paths.is_empty() {
+        return Err("no result files for task".into());
+    }
+
+    let source_thumbnail = embed_thumbnail(task.source_thumbnail.as_ref()).await;
+    // Previews show their first frame; videos a still of the result.
+    let image = if task.preview {
+        existing_paths.first().cloned()
+    } else {
+        embed_thumbnail(task.result_thumbnail.as_ref()).await
+    };
+    let mut attachments = existing_paths.clone();
+    attachments.extend(source_thumbnail.clone());
+    if !task.preview {
+        attachments.extend(image.clone());
+    }
+
+    // Only offer stages that didn't run yet and that a worker pool is configured for.
+    let offer_upscale = !task.stages.contains(&Stage::Upscale) && stage_available(settings, Stage::Upscale);
+    let offer_smooth = !task.stages.contains(&Stage::Interpolate) && stage_available(settings, Stage::Interpolate);
+    let message = post_to_all(&channels, "result", |channel| {
+        let (attachments, author, source_thumbnail, image) = (&attachments, &author, &source_thumbnail, &image);
+        async move {
+            let dst_files = open_files(attachments).await?;
+            let message = channel.send_files(
+                ctx,
+                dst_files.iter().map(|(file, filename)| AttachmentType::File {
+                    file,
+                    filename: filename.clone(),
+                }),
+                |m| {
+                    m.content(format!("<@{}>", task.user_id)).embed(|e| {
+                        embeds::task(e, task_id, task);
+                        if let Some(author) = author {
+                            embeds::author(e, author);
+                        }
+                        if let Some((_, name)) = source_thumbnail {
+                            e.thumbnail(format!("attachment://{}", name));
+                        }
+                        if let Some((_, name)) = image {
+                            e.image(format!("attachment://{}", name));
+                        }
+                        e
+                    });
+                    reply_to_placeholder(m, channel, placeholder);
+                    if task.preview {
+                        m.components(|c| c.create_action_row(|r| r.create_button(|b| {
+                            b.custom_id(format!(
+                                "{}{}",
+                                commands::video_to_video::RENDER_FULL_BUTTON_PREFIX,
+                                task_id,
+                            ))
+                            .label("Render full video")
+                            .style(ButtonStyle::Primary)
+                        })));
+                    } else if offer_upscale || offer_smooth {
+                        m.components(|c| c.create_action_row(|r| {
+                            if offer_upscale {
+                                r.create_button(|b| {
+                                    b.custom_id(format!(
+                                        "{}{}",
+                                        commands::video_to_video::UPSCALE_BUTTON_PREFIX,
+                                        task_id,
+                                    ))
+                                    .label("Upscale")
+                                    .style(ButtonStyle::Secondary)
+                                });
+                            }
+                            if offer_smooth {
+                                r.create_button(|b| {
+                                    b.custom_id(format!(
+                                        "{}{}",
+                                        commands::video_to_video::SMOOTH_BUTTON_PREFIX,
+                                        task_id,
+                                    ))
+                                    .label("Smooth")
+                                    .style(ButtonStyle::Secondary)
+                                });
+                            }
+                            r
+                        }));
+                    }
+                    m
+                },
+            ).await?;
+            Ok(message)
+        }
+    }).await?;
+
+    let status = format!("> Your video is ready. Task ID: **{}.**", task_id);
+    update_placeholder(ctx, placeholder, &message, task.guild_id, status).await;
+    Ok(message.id)
+}
+
+
+/// Timings of the reaper that repairs tasks whose completion or notification got lost.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ReaperConfig {
+    /// Seconds between runs. The first run starts right after logging in.
+    interval_secs: u64,
+    /// Seconds without an update after which a pending task is published again, if its queue
+    /// is empty.
+    stale_after_secs: u64,
+    /// Seconds after submission after which an unfinished task is marked failed, and after
+    /// which failed notifications are no longer retried.
+    expire_after_secs: u64,
+    /// Seconds after which a claimed notification that was never recorded, e.g. because the bot
+    /// crashed while posting it, is claimed again.
+    claim_timeout_secs: u64,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 600,
+            stale_after_secs: 6 * 3600,
+            expire_after_secs: 24 * 3600,
+            claim_timeout_secs: 300,
+        }
+    }
+}
+
+fn seconds_ago(secs: u64) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() - secs as i64 * 1000)
+}
+
+/// Number of messages waiting in `queue`, zero if it doesn't exist. The broker closes the channel
+/// of a passive declare for a missing queue, so the probe gets a channel of its own.
+async fn queued_messages(conn: &lapin::Connection, queue: &str) -> Result<u32, Error> {
+    let channel = conn.create_channel().await?;
+    let declared = channel.queue_declare(
+        queue,
+        QueueDeclareOptions { passive: true, ..Default::default() },
+        FieldTable::default(),
+    ).await;
+    match declared {
+        Ok(declared) => {
+            channel.close(200, "Probed queue").await?;
+            Ok(declared.message_count())
+        },
+        Err(lapin::Error::ProtocolError(e)) if e.get_id() == 404 => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Repairs tasks left behind by a crash or a lost message: finished tasks whose user was never
+/// notified, groups whose last member finished unnoticed, and unfinished tasks that stopped
+/// getting updates. Only tasks submitted through the outbox are considered.
+#[allow(clippy::too_many_arguments)]
+async fn reap(
+    ctx: &serenity::Context,
+    task_collection: &Collection<schemas::VideoStylizerTaskInDB>,
+    group_collection: &Collection<schemas::VideoStylizerGroupInDB>,
+    preference_collection: &Collection<schemas::UserPreferencesInDB>,
+    settings: &BotSettings,
+    conn: &lapin::Connection,
+    channel: &lapin::Channel,
+    queues: &QueueNames,
+    config: &ReaperConfig,
+    ffmpeg_path: &str,
+) -> Result<(), Error> {
+    let documents = task_collection.clone_with_type::<Document>();
+    let stale_before = seconds_ago(config.stale_after_secs);
+    let expire_before = seconds_ago(config.expire_after_secs);
+    let after = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+
+    // Never notified, or claimed by a notification that neither posted nor released it.
+    let unclaimed = doc! {"$or": [
+        {"notified_at": {"$type": "null"}},
+        {
+            "notified_at": {"$lt": seconds_ago(config.claim_timeout_secs)},
+            "notification_message_id": null,
+        },
+    ]};
+
+    let mut unnotified = documents.find(doc! {
+        "status": {"$in": ["completed", "failed"]},
+        "group_id": null,
+        "updated_at": {"$gte": expire_before},
+        "$and": [unclaimed.clone()],
+    }, None).await?;
+    while let Some(document) = unnotified.try_next().await? {
+        let task_id = document.get_object_id("_id")?;
+        let claimed = task_collection.find_one_and_update(
+            doc! {"_id": task_id, "$and": [unclaimed.clone()]},
+            doc! {"$set": {"notified_at": DateTime::now()}},
+            after.clone(),
+        ).await?;
+        if let Some(task) = claimed {
+            tracing::info!(%task_id, "Notifying user of a finished task");
+            let notified = notify_task(ctx, preference_collection, settings, &task_id.to_hex(), &task).await;
+            record_notification(task_collection, task_id, notified).await?;
+        }
+    }
+
+    let mut groups = group_collection.clone_with_type::<Document>().find(doc! {
+        "status": "pending",
+        "created_at": {"$gte": expire_before},
+    }, None).await?;
+    while let Some(document) = groups.try_next().await? {
+        let group_id = document.get_object_id("_id")?.to_hex();
+        if let Err(e) = notify_group(ctx, task_collection, group_collection, preference_collection, &group_id, ffmpeg_path).await {
+            tracing::error!(group_id, error = ?e, "Failed to notify group");
+        }
+    }
+
+    // A task that is still queued may just be waiting behind others, so only tasks whose queue
+    // is empty are published again.
+    let mut queued = Vec::new();
+    for stage in [Stage::Stylize, Stage::Upscale, Stage::Interpolate] {
+        queued.push((stage, queued_messages(conn, queues.pending(stage)).await?));
+    }
+
+    let mut stale = documents.find(doc! {
+        "status": {"$in": ["pending", "processing"]},
+        "outbox": {"$type": "object"},
+        "updated_at": {"$lt": stale_before},
+    }, None).await?;
+    while let Some(document) = stale.try_next().await? {
+        let task_id = document.get_object_id("_id")?;
+        let task: schemas::VideoStylizerTaskInDB = bson::from_document(document)?;
+
+        if task.created_at < expire_before {
+            let error = schemas::TaskError {
+                code: ErrorCode::Expired,
+                detail: format!("Not finished {} seconds after submission", config.expire_after_secs),
+            };
+            let expired = task_collection.find_one_and_update(
+                doc! {"_id": task_id, "status": {"$in": ["pending", "processing"]}},
+                doc! {"$set": {
+                    "status": "failed",
+                    "error": bson::to_bson(&error).unwrap(),
+                    "notified_at": DateTime::now(),
+                    "updated_at": DateTime::now(),
+                }},
+                after.clone(),
+            ).await?;
+            let Some(task) = expired else { continue };
+
+            tracing::warn!(%task_id, "Task expired");
+            metrics::TASKS_FINISHED
+                .with_label_values(&["failed", metrics::style_label(task.style.as_deref()), &metrics::guild_label(task.guild_id)])
+                .inc();
+            if let Some(group_id) = &task.group_id {
+                if let Err(e) = notify_group(ctx, task_collection, group_collection, preference_collection, group_id, ffmpeg_path).await {
+                    tracing::error!(group_id, error = ?e, "Failed to notify group");
+                }
+            } else {
+                let notified = notify_task(ctx, preference_collection, settings, &task_id.to_hex(), &task).await;
+                record_notification(task_collection, task_id, notified).await?;
+            }
+        } else if task.status == "pending" {
+            let stage = task.stages.first().copied().unwrap_or(Stage::Stylize);
+            if queued.iter().any(|(queued_stage, count)| *queued_stage == stage && *count > 0) {
+                continue;
+            }
+
+            tracing::warn!(%task_id, "Publishing stale task again");
+            task_collection.update_one(
+                doc! {"_id": task_id},
+                doc! {"$set": {"outbox.sent_at": null, "updated_at": DateTime::now()}},
+                None,
+            ).await?;
+            outbox::relay(task_collection, channel, queues, &task_id.to_hex(), task.into()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// TOML file with settings, database and queue names and style presets.
+    #[clap(long, env = "BOT_CONFIG")]
+    config: Option<PathBuf>,
+    #[clap(long, env, hide_env_values = true)]
+    discord_token: Option<Secret>,
+    #[clap(long, env, hide_env_values = true)]
+    mongo_uri: Option<Secret>,
+    #[clap(long, env = "FFMPEG_PATH")]
+    ffmpeg: Option<String>,
+    /// Prefix of text commands.
+    #[clap(long, env)]
+    prefix: Option<String>,
+    /// Largest video accepted by the commands, in megabytes.
+    #[clap(long, env)]
+    max_file_size_mb: Option<u64>,
+    /// Address of the HTTP server exposing `/metrics`. Defaults to `0.0.0.0:9090`.
+    #[clap(long, env)]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(flatten)]
+    amqp: amqp::AmqpConfig,
+    #[clap(flatten)]
+    log: LogConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    discord_token: Option<Secret>,
+    mongo_uri: Option<Secret>,
+    ffmpeg: Option<String>,
+    prefix: Option<String>,
+    max_file_size_mb: Option<u64>,
+    metrics_addr: Option<SocketAddr>,
+    db: db::DbNames,
+    queues: amqp::QueueNames,
+    reaper: ReaperConfig,
+    /// Stages after stylization that a worker pool is configured for, e.g.
+    /// `["upscale", "interpolate"]`. Without one, users can't request the stage.
+    optional_stages: Vec<Stage>,
+    /// Prompts overriding the built-in styles, keyed by display name, e.g. `"Oil Painting"`.
+    style_presets: HashMap<String, String>,
+}
+
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let args = Args::parse();
+    let _telemetry = telemetry::init(&args.log, "omni-bot").unwrap();
+    tracing::info!("Starting bot");
+    tracing::debug!(?args, "Parsed arguments");
+
+    let config_file: ConfigFile = match &args.config {
+        Some(path) => config::load_toml(path).await.unwrap(),
+        None => ConfigFile::default(),
+    };
+
+    let discord_token = args.discord_token.or(config_file.discord_token)
+        .expect("Expected a Discord token in --discord-token, DISCORD_TOKEN or the config file");
+    let mongo_uri = args.mongo_uri.or(config_file.mongo_uri)
+        .map_or("mongodb://localhost:27017".to_owned(), |uri| uri.expose().to_owned());
+    let ffmpeg_path = args.ffmpeg.or(config_file.ffmpeg).unwrap_or("ffmpeg".to_owned());
+    let prefix = args.prefix.or(config_file.prefix).unwrap_or("~".to_owned());
+    let metrics_addr = args.metrics_addr.or(config_file.metrics_addr)
+        .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 9090)));
+
+    let mut settings = BotSettings {
+        style_presets: config_file.style_presets,
+        queues: config_file.queues,
+        optional_stages: config_file.optional_stages,
+        ..Default::default()
+    };
+    if let Some(max_file_size_mb) = args.max_file_size_mb.or(config_file.max_file_size_mb) {
+        settings.max_file_size = max_file_size_mb * 1024 * 1024;
+    }
+    let completed_queue = settings.queues.completed.clone();
+    let settings = Arc::new(settings);
+    let user_settings = settings.clone();
 
-    let mongo_uri = env::var("MONGO_URI").unwrap_or("mongodb://localhost:27017".to_owned());
-    let (video_stylizer_task_collection,) = db::setup_db(mongo_uri).await;
+    let (
+        database,
+        video_stylizer_task_collection,
+        video_stylizer_group_collection,
+        user_preference_collection,
+    ) = db::setup_db(&mongo_uri, &config_file.db).await;
     let video_stylizer_task_collection_clone = video_stylizer_task_collection.clone();
+    let video_stylizer_group_collection_clone = video_stylizer_group_collection.clone();
+    let user_preference_collection_clone = user_preference_collection.clone();
 
-    let amqp_uri = env::var("AMQP_URI").unwrap_or("amqp://localhost:5672".to_owned());
-    let (sending_channel, receiving_channel) = amqp::setup_amqp(amqp_uri).await;
+    let (amqp_conn, sending_channel, receiving_channel) = amqp::setup_amqp(&args.amqp, &settings.queues).await;
+    let reaper_channel = sending_channel.clone();
+    let queues = settings.queues.clone();
+    let channels = [sending_channel.clone(), receiving_channel.clone()];
+    match outbox::relay_pending(&video_stylizer_task_collection, &sending_channel, &settings.queues).await {
+        Ok(0) => {},
+        Ok(relayed) => tracing::info!(relayed, "Published tasks left in the outbox"),
+        Err(e) => tracing::error!(error = ?e, "Failed to publish tasks left in the outbox"),
+    }
+    let in_flight = InFlight::default();
+
+    let health = Health::default();
+    health.add_check("mongodb", move || {
+        let database = database.clone();
+        async move { db::ping(&database).await }
+    });
+    health.add_check("amqp", {
+        let channels = channels.clone();
+        move || {
+            let result = channels.iter().try_for_each(health::channel_connected);
+            async move { result }
+        }
+    });
+
+    let metrics_health = health.clone();
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_addr, metrics_health).await {
+            tracing::error!(error = ?e, "Metrics server failed");
+        }
+    });
 
     let options = poise::FrameworkOptions {
-        commands: vec![commands::help(), commands::video_to_video::video_stylizer()],
+        commands: vec![
+            commands::help(),
+            // Before the slash command of the same name, which text commands would match first.
+            commands::video_to_video::video_stylizer_prefix(),
+            commands::video_to_video::video_stylizer(),
+            commands::video_to_video::video_style_compare(),
+            commands::settings::notify_by_dm(),
+        ],
         prefix_options: poise::PrefixFrameworkOptions {
-            prefix: Some("~".into()),
+            prefix: Some(prefix),
             edit_tracker: Some(poise::EditTracker::for_timespan(Duration::from_secs(3600),)),
             ..Default::default()
         },
         on_error: |error| Box::pin(on_error(error)),
         pre_command: |ctx| {
             Box::pin(async move {
-                println!("Executing command {}...", ctx.command().qualified_name);
+                tracing::info!(command = %ctx.command().qualified_name, "Executing command");
             })
         },
         post_command: |ctx| {
             Box::pin(async move {
-                println!("Executed command {}!", ctx.command().qualified_name);
+                tracing::info!(command = %ctx.command().qualified_name, "Executed command");
             })
         },
-        event_handler: |_ctx, event, _framework, _data| {
+        event_handler: |ctx, event, _framework, data| {
             Box::pin(async move {
-                println!("Got an event in event handler: {:?}", event.name());
+                tracing::debug!(event = event.name(), "Got an event in event handler");
+                if let poise::Event::InteractionCreate {
+                    interaction: serenity::Interaction::MessageComponent(component),
+                } = event {
+                    commands::video_to_video::handle_component(ctx, component, data).await?;
+                }
                 Ok(())
             })
         },
@@ -61,19 +835,21 @@ async fn main() {
     };
 
     let (ctx_sender, mut ctx_receiver) = mpsc::channel(1);
+    let user_in_flight = in_flight.clone();
     let framework = poise::Framework::builder()
-        .token(
-            env::var("DISCORD_TOKEN")
-                .expect("Expected a token in the environment")
-        )
+        .token(discord_token.expose())
         .setup(move |ctx, ready, framework| {
             Box::pin(async move {
-                println!("Logged in as {}", ready.user.name);
+                tracing::info!(user = %ready.user.name, "Logged in");
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                 ctx_sender.send(ctx.clone()).await.unwrap();
                 Ok(UserData {
                     video_stylizer_task_collection: Arc::new(video_stylizer_task_collection),
+                    video_stylizer_group_collection: Arc::new(video_stylizer_group_collection),
+                    user_preference_collection: Arc::new(user_preference_collection),
                     video_stylizer_task_pending_channel: Arc::new(sending_channel),
+                    settings: user_settings,
+                    in_flight: user_in_flight,
                 })
             })
         })
@@ -81,6 +857,37 @@ async fn main() {
         .intents(serenity::GatewayIntents::non_privileged() | serenity::GatewayIntents::MESSAGE_CONTENT)
         .build().await.unwrap();
 
+    health.add_check("discord", {
+        let shard_manager = framework.shard_manager().clone();
+        move || {
+            let shard_manager = shard_manager.clone();
+            async move {
+                let shard_manager = shard_manager.lock().await;
+                let runners = shard_manager.runners.lock().await;
+                if runners.is_empty() {
+                    return Err("no shards are running".into());
+                }
+                for (shard_id, runner) in runners.iter() {
+                    if runner.stage != ConnectionStage::Connected {
+                        return Err(format!("shard {} is {:?}", shard_id.0, runner.stage).into());
+                    }
+                }
+                Ok(())
+            }
+        }
+    });
+
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::terminate_signal().await;
+        tracing::info!("Shutting down, signal again to exit immediately");
+        shutdown_sender.send_replace(true);
+        shutdown::terminate_signal().await;
+        tracing::warn!("Exiting without waiting for in-flight work");
+        std::process::exit(1);
+    });
+
+    let shard_manager = framework.shard_manager().clone();
     let bot_server = async {
         framework
             .start()
@@ -88,110 +895,128 @@ async fn main() {
             .unwrap();
     };
 
+    let mut shutdown_requested = shutdown_receiver.clone();
     let task_callback = async {
-        let ctx = ctx_receiver.recv().await.unwrap();
+        let mut shutdown_requested = shutdown_receiver;
+        let ctx = tokio::select! {
+            ctx = ctx_receiver.recv() => ctx.unwrap(),
+            _ = shutdown_requested.wait_for(|requested| *requested) => return,
+        };
 
+        receiving_channel.basic_qos(1, BasicQosOptions::default()).await.unwrap();
         let mut consumer = receiving_channel.basic_consume(
-            "completedVideoStylizerTasks",
+            &completed_queue,
             "bot",
             BasicConsumeOptions::default(),
             FieldTable::default(),
         ).await.unwrap();
 
-        while let Some(delivery) = consumer.next().await {
-            let delivery = delivery.expect("error in consumer");
-            let _task = serde_json::from_slice::<schemas::VideoStylizerTaskInQueue>(
-                &delivery.data
-            );
-            if let Ok(task) = _task {
-                println!("Got task: {:?}", task);
-                video_stylizer_task_collection_clone.update_one(
-                    doc! {"_id": task.task_id.clone()},
-                    doc! {"$set": {"status": task.status.clone(), "result": task.result.clone().unwrap()}},
-                    None
-                ).await.unwrap();
-
-                match task.status.as_str() {
-                    "completed" => {
-                        if task.result.is_none() {
-                            eprintln!("Task result is none: {:?}", task);
-                            continue;
-                        }
-
-                        let dst_video_path = task.result.unwrap();
-
-                        if !tokio::fs::try_exists(&dst_video_path).await.unwrap() {
-                            eprintln!("File does not exist: {:?}", dst_video_path);
-                            continue;
-                        }
-
-                        let dst_video_name = dst_video_path.split("/").last().unwrap().to_owned();
-                        let dst_video_file = tokio::fs::File::open(dst_video_path).await.unwrap();
-
-                        let channel = ChannelId(task.channel_id).to_channel(&ctx).await;
-                        if let Ok(Channel::Guild(channel)) = channel {
-                            let mut responses = Vec::with_capacity(6);
-                            responses.push(
-                                format!(
-                                    "New generation from <@{}>:\nTask: **Video Stylization**\nTask ID: {}",
-                                    task.user_id,
-                                    task.task_id,
-                                )
-                            );
-
-                            if let Some(video_prompt) = task.video_prompt {
-                                responses.push(format!("Video Prompt: {}", video_prompt));
-                            }
-
-                            responses.push(format!("Style Prompt: {}", task.style_prompt));
-
-                            if let Some(negative_prompt) = task.negative_prompt {
-                                responses.push(format!("Negative Prompt: {}", negative_prompt));
-                            }
+        // The reaper runs between completions so it never races one for the same task.
+        let mut reap_interval = tokio::time::interval(Duration::from_secs(config_file.reaper.interval_secs));
+        reap_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-                            if let Some(max_keyframes) = task.max_keyframes {
-                                responses.push(format!("Max Keyframes: {}", max_keyframes));
-                            }
-
-                            responses.push(format!("Seed: {}", task.seed));
-
-                            let response = responses.join("\n");
+        health.loop_started("completion_consumer");
+        loop {
+            // A completion that is being handled always runs to its ack, so nothing is posted twice.
+            let delivery = tokio::select! {
+                delivery = consumer.next() => delivery,
+                _ = reap_interval.tick() => {
+                    let reaped = reap(
+                        &ctx,
+                        &video_stylizer_task_collection_clone,
+                        &video_stylizer_group_collection_clone,
+                        &user_preference_collection_clone,
+                        &settings,
+                        &amqp_conn,
+                        &reaper_channel,
+                        &queues,
+                        &config_file.reaper,
+                        &ffmpeg_path,
+                    ).instrument(tracing::info_span!("reap")).await;
+                    if let Err(e) = reaped {
+                        tracing::error!(error = ?e, "Failed to reap tasks");
+                    }
+                    continue;
+                },
+                _ = shutdown_requested.wait_for(|requested| *requested) => break,
+            };
+            let delivery = match delivery {
+                Some(Ok(delivery)) => delivery,
+                Some(Err(e)) => {
+                    tracing::error!(error = ?e, "Error in completion consumer");
+                    health.loop_ended("completion_consumer");
+                    return;
+                },
+                None => {
+                    health.loop_ended("completion_consumer");
+                    return;
+                },
+            };
+            metrics::observe_queue_latency(&completed_queue, &delivery.properties);
 
-                            channel.send_files(
-                                &ctx,
-                                [AttachmentType::File { file: &dst_video_file, filename: dst_video_name }],
-                                |m| m.content(response),
-                            ).await.unwrap();
-                        } else {
-                            eprintln!("Failed to get channel. {:?}", channel);
-                        }
-                    },
-                    "failed" => {
-                        let channel = ChannelId(task.channel_id).to_channel(&ctx).await;
-                        if let Ok(Channel::Guild(channel)) = channel {
-                            channel.say(
-                                &ctx,
-                                format!(
-                                    "> Failed to stylize your video. <@{}> Error: {:?}", task.user_id, task.result.unwrap()
-                                ),
-                            ).await.unwrap();
-                        } else {
-                            eprintln!("Failed to get channel. {:?}", channel);
-                        }
-                    },
-                    _ => {
-                        eprintln!("Invalid task status: {:?}", task);
+            match serde_json::from_slice::<schemas::VideoStylizerTaskInQueue>(&delivery.data) {
+                Ok(task) => {
+                    let span = tracing::info_span!(
+                        "handle_completion",
+                        task_id = %task.task_id,
+                        status = %task.status,
+                    );
+                    telemetry::set_parent_from_headers(&span, &delivery.properties);
+                    let handled = handle_completion(
+                        &ctx,
+                        task,
+                        &video_stylizer_task_collection_clone,
+                        &video_stylizer_group_collection_clone,
+                        &user_preference_collection_clone,
+                        &settings,
+                        &ffmpeg_path,
+                    ).instrument(span).await;
+                    if let Err(e) = handled {
+                        tracing::error!(error = ?e, "Failed to record task update, returning it to the queue");
+                        delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await.expect("nack");
+                        continue;
                     }
-                }
-            } else {
-                eprintln!("Failed to deserialize task.");
+                },
+                Err(e) => tracing::error!(error = ?e, "Failed to deserialize task"),
             }
             delivery.ack(BasicAckOptions::default()).await.expect("ack");
         }
+
+        receiving_channel.basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default()).await.unwrap();
+        while let Some(Ok(delivery)) = consumer.next().await {
+            delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await.expect("nack");
+        }
+        tracing::info!("Stopped consuming completions");
     };
 
-    tokio::select! {
-        _ = bot_server => {},
-        _ = task_callback => {},
+    tokio::pin!(bot_server, task_callback);
+    let shutting_down = tokio::select! {
+        _ = &mut bot_server => {
+            tracing::error!("Discord client stopped");
+            false
+        },
+        _ = &mut task_callback => {
+            tracing::error!("Completion consumer stopped");
+            false
+        },
+        _ = shutdown_requested.wait_for(|requested| *requested) => true,
+    };
+    if !shutting_down {
+        // Exit non-zero so the container gets restarted.
+        std::process::exit(1);
+    }
+
+    // Stop taking commands first, then let submissions that already started reach the queue.
+    shard_manager.lock().await.shutdown_all().await;
+    bot_server.await;
+    tracing::info!("Disconnected from Discord");
+    in_flight.wait_idle().await;
+    task_callback.await;
+
+    for channel in channels {
+        if let Err(e) = channel.close(200, "Bot shutting down").await {
+            tracing::warn!(error = ?e, "Failed to close channel");
+        }
     }
+    tracing::info!("Bot stopped");
 }
@@ -1,16 +1,101 @@
 use clap::Parser;
 use futures::StreamExt;
-use lapin::{options::{BasicConsumeOptions, BasicAckOptions, BasicNackOptions, BasicPublishOptions}, BasicProperties, Connection, ConnectionProperties, options::QueueDeclareOptions, types::FieldTable};
+use lapin::{message::Delivery, options::{BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions}, types::FieldTable};
+use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
-use omni_bot_rs::schemas::VideoStylizerTaskInQueue;
+use omni_bot_rs::{Error, amqp::{self, AmqpConfig, QueueNames}, backend::{self, Backend, BackendConfig, BackendError, BackendOptions}, config::{self, Secret}, error_code::ErrorCode, ffmpeg, health::{self, Health}, metrics, schemas::{AudioSource, Stage, StageArtifact, VideoStylizerTaskInQueue}, telemetry::{self, LogConfig}};
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch};
+use tracing::Instrument;
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// TOML file with additional `[[backends]]` entries and their credentials.
+    #[clap(long, env = "WORKER_CONFIG")]
+    config: Option<PathBuf>,
     #[clap(long, env)]
     backend: Vec<String>,
-    #[clap(default_value = "amqp://localhost:5672", long, env)]
-    amqp_uri: String,
+    #[clap(long, env)]
+    upscale_backend: Vec<String>,
+    #[clap(long, env)]
+    interpolate_backend: Vec<String>,
+    #[clap(flatten)]
+    amqp: AmqpConfig,
+    #[clap(flatten)]
+    log: LogConfig,
+    /// Address of the HTTP server exposing `/metrics`.
+    #[clap(default_value = "0.0.0.0:9091", long, env)]
+    metrics_addr: SocketAddr,
+    /// Backends given on the command line trim, resize and resample the source themselves,
+    /// so they get the options instead of a source preprocessed by the worker.
+    #[clap(long, env)]
+    native_preprocess: bool,
+    #[clap(default_value = "ffmpeg", long, env)]
+    ffmpeg: String,
+    /// Directory for sources preprocessed by the worker. Backends read them by path, so remote
+    /// backends need it on a volume they share with the worker, mounted at the same path.
+    #[clap(default_value = "/tmp/omni-bot", long, env)]
+    work_dir: PathBuf,
+    /// Length in seconds of the clip rendered for preview tasks.
+    #[clap(default_value_t = 2.0, long, env)]
+    preview_duration: f64,
+    /// Number of still frames posted for preview tasks.
+    #[clap(default_value_t = 3, long, env)]
+    preview_frames: u64,
+    #[clap(default_value_t = 2, long, env)]
+    upscale_factor: u64,
+    #[clap(default_value_t = 2, long, env)]
+    interpolation_multiplier: u64,
+    /// Seconds to wait for a TCP connection to a backend.
+    #[clap(default_value_t = 10, long, env)]
+    backend_connect_timeout: u64,
+    /// Seconds a blocking backend request may take, including the render.
+    #[clap(default_value_t = 3600, long, env)]
+    backend_request_timeout: u64,
+    /// Seconds a backend may pause while sending a response body.
+    #[clap(default_value_t = 60, long, env)]
+    backend_read_timeout: u64,
+    /// Seconds between status polls of job-based backends.
+    #[clap(default_value_t = 5, long, env)]
+    backend_poll_interval: u64,
+    /// Seconds a job may run on a job-based backend before it is cancelled.
+    #[clap(default_value_t = 3600, long, env)]
+    backend_poll_deadline: u64,
+    /// Times a stage is retried after a temporary backend failure before it is dead-lettered.
+    #[clap(default_value_t = 5, long, env)]
+    max_retries: u32,
+    /// Seconds to wait before retrying a stage.
+    #[clap(default_value_t = 30, long, env)]
+    retry_delay: u64,
+    /// Seconds in-flight tasks may keep running after `SIGTERM` before they are requeued.
+    #[clap(default_value_t = 300, long, env)]
+    shutdown_timeout: u64,
+    /// Bearer token sent to backends given on the command line.
+    #[clap(long, env, hide_env_values = true)]
+    backend_token: Option<Secret>,
+    /// API key sent to backends given on the command line.
+    #[clap(long, env, hide_env_values = true)]
+    backend_api_key: Option<Secret>,
+    #[clap(default_value = "X-API-Key", long, env)]
+    backend_api_key_header: String,
+    /// PEM bundle of extra CA certificates trusted for backends given on the command line.
+    #[clap(long, env)]
+    backend_ca_cert: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS with backends given on the command line.
+    #[clap(long, env)]
+    backend_client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM key of the client certificate.
+    #[clap(long, env)]
+    backend_client_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    backends: Vec<BackendConfig>,
+    #[serde(default)]
+    queues: QueueNames,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -21,103 +106,684 @@ pub struct VideoStylizerRequestBody {
     pub n_prompt: String,
     pub max_keyframe: i64,
     pub seed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct VideoStylizerResponseBody {
-    pub output_path: String,
+pub struct VideoUpscaleRequestBody {
+    pub videoname: String,
+    pub scale: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VideoInterpolationRequestBody {
+    pub videoname: String,
+    pub multiplier: u64,
+}
+
+struct WorkerConfig {
+    ffmpeg: String,
+    work_dir: PathBuf,
+    preview_duration: f64,
+    preview_frames: u64,
+    upscale_factor: u64,
+    interpolation_multiplier: u64,
+    max_retries: u32,
+    retry_delay: Duration,
+    shutdown_timeout: Duration,
+    queues: QueueNames,
+}
+
+/// A backend with the settings of its [`BackendConfig`] that apply to each task.
+struct ConsumerBackend {
+    backend: Arc<dyn Backend>,
+    /// URL of the backend, used in logs and metric labels.
+    name: String,
+    native_preprocess: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WorkerState {
+    Running,
+    /// Finishing in-flight tasks without taking new ones, e.g. during backend maintenance.
+    Draining,
+    ShuttingDown,
+}
+
+/// Drives the worker state from signals: `SIGTERM`/`SIGINT` shut down gracefully (a second one
+/// exits immediately), `SIGUSR1` starts draining and `SIGUSR2` resumes consuming.
+async fn handle_signals(state: watch::Sender<WorkerState>) {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    let mut drain = signal(SignalKind::user_defined1()).unwrap();
+    let mut resume = signal(SignalKind::user_defined2()).unwrap();
+
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = interrupt.recv() => {},
+            _ = drain.recv() => {
+                if state.send_if_modified(|state| replace_if(state, WorkerState::Running, WorkerState::Draining)) {
+                    tracing::info!("Draining, no new tasks will be taken");
+                }
+                continue;
+            },
+            _ = resume.recv() => {
+                if state.send_if_modified(|state| replace_if(state, WorkerState::Draining, WorkerState::Running)) {
+                    tracing::info!("Resuming after drain");
+                }
+                continue;
+            },
+        }
+
+        if state.send_replace(WorkerState::ShuttingDown) == WorkerState::ShuttingDown {
+            tracing::warn!("Forced shutdown");
+            std::process::exit(1);
+        }
+        tracing::info!("Shutting down, waiting for in-flight tasks");
+    }
+}
+
+fn replace_if(state: &mut WorkerState, from: WorkerState, to: WorkerState) -> bool {
+    if *state == from {
+        *state = to;
+        true
+    } else {
+        false
+    }
+}
+
+/// What to do with a delivery once its stage has been attempted.
+enum Outcome {
+    /// The stage produced an artifact and the task moves on to its next stage.
+    Next(VideoStylizerTaskInQueue),
+    /// The task is finished, successfully or not.
+    Done(VideoStylizerTaskInQueue),
+    /// The backend failed temporarily; run the stage again later.
+    Retry(VideoStylizerTaskInQueue),
+    /// The backend failed for good; keep the task for inspection and tell the user.
+    DeadLetter(VideoStylizerTaskInQueue),
+}
+
+async fn publish(channel: &lapin::Channel, queue: &str, task: &VideoStylizerTaskInQueue) -> Result<(), Error> {
+    let payload = serde_json::to_vec(task).unwrap();
+    amqp::publish(channel, queue, &payload, amqp::task_properties(&task.task_id)).await
+}
+
+/// Publishes a task to the retry queue of `stage`, from which it returns to the stage's queue
+/// after `delay`. The delivery can be acked right away instead of blocking the consumer.
+async fn publish_retry(
+    channel: &lapin::Channel,
+    queues: &QueueNames,
+    stage: Stage,
+    task: &VideoStylizerTaskInQueue,
+    delay: Duration,
+) -> Result<(), Error> {
+    let payload = serde_json::to_vec(task).unwrap();
+    let properties = amqp::task_properties(&task.task_id)
+        .with_expiration(delay.as_millis().to_string().into());
+    amqp::publish(channel, &queues.retry(stage), &payload, properties).await
+}
+
+/// Builds the backend request for the stylize stage. Backends with `native_preprocess` get the
+/// trim/resize/fps options; all others get a source the worker preprocessed in `work_dir`.
+///
+/// Either way the video is cut to `start`..`end`, which is recorded as the task's audio source.
+async fn stylize_request(
+    task: &mut VideoStylizerTaskInQueue,
+    config: &WorkerConfig,
+    native_preprocess: bool,
+) -> Result<VideoStylizerRequestBody, Error> {
+    let mut preprocess_options = ffmpeg::PreprocessOptions {
+        start: task.start,
+        end: task.end,
+        max_width: task.max_width,
+        max_height: task.max_height,
+        fps: task.fps,
+    };
+    let mut max_keyframes = task.max_keyframes;
+
+    if task.preview {
+        let preview_end = task.start.unwrap_or(0.0) + config.preview_duration;
+        preprocess_options.end = Some(task.end.map_or(preview_end, |end| end.min(preview_end)));
+        max_keyframes = Some(max_keyframes.map_or(config.preview_frames, |n| n.min(config.preview_frames)).max(2));
+    }
+
+    let mut request_body = VideoStylizerRequestBody {
+        videoname: task.stage_input().to_owned(),
+        video_prompt: task.video_prompt.clone().unwrap_or_default(),
+        style_prompt: task.style_prompt.clone(),
+        n_prompt: task.negative_prompt.clone().unwrap_or_default(),
+        max_keyframe: max_keyframes.map_or(-1, |n| n as i64),
+        seed: task.seed,
+        start: None,
+        end: None,
+        max_width: None,
+        max_height: None,
+        fps: None,
+    };
+
+    task.audio = Some(AudioSource {
+        url: task.src_video_url.clone(),
+        start: preprocess_options.start,
+        end: preprocess_options.end,
+    });
+
+    if native_preprocess {
+        request_body.start = preprocess_options.start;
+        request_body.end = preprocess_options.end;
+        request_body.max_width = preprocess_options.max_width;
+        request_body.max_height = preprocess_options.max_height;
+        request_body.fps = preprocess_options.fps;
+    } else if !preprocess_options.is_empty() {
+        let src_video_path = preprocessed_source(config, &task.task_id);
+        ffmpeg::preprocess(&config.ffmpeg, task.stage_input(), &src_video_path, &preprocess_options).await?;
+        request_body.videoname = src_video_path.to_string_lossy().into_owned();
+    }
+
+    Ok(request_body)
+}
+
+/// Source video preprocessed for a backend, removed once the stage ran.
+fn preprocessed_source(config: &WorkerConfig, task_id: &str) -> PathBuf {
+    config.work_dir.join(format!("{}_src.mp4", task_id))
+}
+
+/// Path of a file written next to the backend's output. Results are read by the bot, which
+/// shares the backend's output volume but not the worker's `work_dir`.
+fn output_sibling(output_path: &str, name: &str) -> PathBuf {
+    Path::new(output_path).with_file_name(name)
+}
+
+/// Renders a still of `input` next to the backend's output for embeds. Results are still posted
+/// without it, so failures are only logged.
+async fn render_thumbnail(config: &WorkerConfig, input: &str, output_path: &str, name: &str) -> Option<String> {
+    let path = output_sibling(output_path, name);
+    match ffmpeg::thumbnail(&config.ffmpeg, input, &path, 320).await {
+        Ok(()) => Some(path.to_string_lossy().into_owned()),
+        Err(e) => {
+            tracing::warn!(error = ?e, "Failed to render thumbnail");
+            None
+        },
+    }
+}
+
+/// Runs the post-processing that only applies once the last stage is done:
+/// preview frames, audio, output format and thumbnails.
+async fn finish(
+    mut task: VideoStylizerTaskInQueue,
+    output_path: String,
+    config: &WorkerConfig,
+) -> VideoStylizerTaskInQueue {
+    let source_thumbnail = format!("{}_source.jpg", task.task_id);
+    task.source_thumbnail = render_thumbnail(config, &task.src_video_url, &output_path, &source_thumbnail).await;
+
+    if task.preview {
+        let output_dir = Path::new(&output_path).parent().unwrap_or(Path::new("."));
+        return match ffmpeg::extract_frames(
+            &config.ffmpeg,
+            &output_path,
+            output_dir,
+            &format!("{}_preview", task.task_id),
+            config.preview_frames,
+            config.preview_duration,
+        ).await {
+            Ok(frames) => {
+                task.preview_frames = frames;
+                task.with_result("completed".to_owned(), output_path)
+            },
+            Err(e) => task.with_error(ErrorCode::MediaProcessingFailed, format!("{:?}", e)),
+        };
+    }
+
+    let mut result = output_path.clone();
+
+    if let (true, Some(audio)) = (task.keep_audio, &task.audio) {
+        let dst_video_path = output_sibling(&output_path, &format!("{}_audio.mp4", task.task_id));
+        match ffmpeg::mux_audio(
+            &config.ffmpeg,
+            &result,
+            &audio.url,
+            &dst_video_path,
+            audio.start,
+            audio.end,
+        ).await {
+            Ok(()) => result = dst_video_path.to_string_lossy().into_owned(),
+            // A silent video is still a useful result.
+            Err(e) => tracing::warn!(error = ?e, "Failed to restore audio"),
+        }
+    }
+
+    let extension = task.output_format.extension();
+    if !result.ends_with(&format!(".{extension}")) {
+        let dst_video_path = output_sibling(&output_path, &format!("{}.{}", task.task_id, extension));
+        match ffmpeg::transcode(&config.ffmpeg, &result, &dst_video_path, task.output_format).await {
+            Ok(()) => {
+                if result != output_path {
+                    remove_file(&result).await;
+                }
+                result = dst_video_path.to_string_lossy().into_owned();
+            },
+            Err(e) => return task.with_error(ErrorCode::MediaProcessingFailed, format!("{:?}", e)),
+        }
+    }
+
+    let result_thumbnail = format!("{}_result.jpg", task.task_id);
+    task.result_thumbnail = render_thumbnail(config, &result, &output_path, &result_thumbnail).await;
+
+    task.with_result("completed".to_owned(), result)
+}
+
+/// Removes an intermediate file, which only wastes space if it is left behind.
+async fn remove_file(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(?path, error = ?e, "Failed to remove intermediate file");
+        }
+    }
+}
+
+async fn process(
+    stage: Stage,
+    backend: &ConsumerBackend,
+    mut task: VideoStylizerTaskInQueue,
+    config: &WorkerConfig,
+) -> Outcome {
+    let backend_name = backend.name.as_str();
+    let request = match stage {
+        Stage::Stylize => match stylize_request(&mut task, config, backend.native_preprocess).await {
+            Ok(request_body) => serde_json::to_value(request_body),
+            Err(e) => {
+                tracing::warn!(error = ?e, "Failed to preprocess video");
+                return Outcome::Done(task.with_error(ErrorCode::InputInvalid, format!("{:?}", e)));
+            },
+        },
+        Stage::Upscale => serde_json::to_value(VideoUpscaleRequestBody {
+            videoname: task.stage_input().to_owned(),
+            scale: config.upscale_factor,
+        }),
+        Stage::Interpolate => serde_json::to_value(VideoInterpolationRequestBody {
+            videoname: task.stage_input().to_owned(),
+            multiplier: config.interpolation_multiplier,
+        }),
+    }.unwrap();
+
+    let timer = metrics::BACKEND_REQUEST_DURATION
+        .with_label_values(&[backend_name, stage.as_str()])
+        .start_timer();
+    let result = backend.backend.run(&request).instrument(tracing::info_span!("backend_request")).await;
+    timer.observe_duration();
+
+    if let Err(e) = &result {
+        let class = match e {
+            BackendError::Retryable(_) => "retryable",
+            BackendError::Permanent(_) => "permanent",
+            BackendError::InvalidInput(_) => "invalid_input",
+        };
+        metrics::BACKEND_ERRORS.with_label_values(&[backend_name, stage.as_str(), class]).inc();
+    }
+
+    let output_path = match result {
+        Ok(output_path) => output_path,
+        Err(BackendError::Retryable(e)) if task.attempts < config.max_retries => {
+            tracing::warn!(attempts = task.attempts, error = ?e, "Backend failed temporarily, retrying task");
+            task.attempts += 1;
+            return Outcome::Retry(task);
+        },
+        Err(BackendError::Retryable(e)) => {
+            tracing::error!(error = ?e, "Backend still unavailable, dead-lettering task");
+            return Outcome::DeadLetter(task.with_error(ErrorCode::BackendUnavailable, format!("{:?}", e)));
+        },
+        Err(BackendError::Permanent(e)) => {
+            tracing::error!(error = ?e, "Backend failed, dead-lettering task");
+            return Outcome::DeadLetter(task.with_error(ErrorCode::BackendFailed, format!("{:?}", e)));
+        },
+        Err(BackendError::InvalidInput(body)) => {
+            let code = body.code.as_deref()
+                .and_then(ErrorCode::from_backend_code)
+                .unwrap_or(ErrorCode::InputInvalid);
+            return Outcome::Done(task.with_error(code, body.to_string()));
+        },
+    };
+
+    task.attempts = 0;
+    task.artifacts.push(StageArtifact {
+        stage,
+        output_path: output_path.clone(),
+        completed_at: DateTime::now(),
+    });
+
+    // Previews only ever run the stylize stage.
+    if task.next_stage().is_some() && !task.preview {
+        task.status = "processing".to_owned();
+        Outcome::Next(task)
+    } else {
+        Outcome::Done(finish(task, output_path, config).await)
+    }
+}
+
+async fn consume(
+    stage: Stage,
+    backend: ConsumerBackend,
+    amqp_config: AmqpConfig,
+    config: Arc<WorkerConfig>,
+    health: Health,
+    mut state: watch::Receiver<WorkerState>,
+) -> Result<(), Error> {
+    let name = format!("{}:{}", stage.as_str(), backend.name);
+    let conn = amqp::connect(&amqp_config, "omni-worker").await.unwrap();
+
+    let sending_channel = amqp::confirm_channel(&conn).await.unwrap();
+    let receiving_channel = conn.create_channel().await.unwrap();
+
+    health.add_check(format!("amqp:{}", name), {
+        let channels = [sending_channel.clone(), receiving_channel.clone()];
+        move || {
+            let result = channels.iter().try_for_each(health::channel_connected);
+            async move { result }
+        }
+    });
+
+    let options = amqp::task_queue_options();
+    let queues = &config.queues;
+    sending_channel.queue_declare(
+        &queues.completed, options, FieldTable::default()
+    ).await.unwrap();
+
+    // Tasks that failed for good, kept for inspection by admins.
+    sending_channel.queue_declare(
+        &queues.dead_letter, options, FieldTable::default()
+    ).await.unwrap();
+
+    for next_stage in [Stage::Upscale, Stage::Interpolate] {
+        sending_channel.queue_declare(
+            queues.pending(next_stage), options, FieldTable::default()
+        ).await.unwrap();
+    }
+
+    receiving_channel.queue_declare(
+        queues.pending(stage), options, FieldTable::default()
+    ).await.unwrap();
+
+    sending_channel.queue_declare(
+        &queues.retry(stage), options, amqp::retry_queue_arguments(queues, stage)
+    ).await.unwrap();
+
+    // Take one task at a time, so stopping never strands prefetched tasks on this worker.
+    receiving_channel.basic_qos(1, BasicQosOptions::default()).await.unwrap();
+
+    health.loop_started(format!("consumer:{}", name));
+    loop {
+        let current = *state.wait_for(|state| *state != WorkerState::Draining).await.unwrap();
+        if current == WorkerState::ShuttingDown {
+            break;
+        }
+
+        let mut consumer = receiving_channel.basic_consume(
+            queues.pending(stage),
+            "worker",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        ).await.unwrap();
+        tracing::info!(consumer = %name, "Consuming tasks");
+
+        loop {
+            // Checked first, so a delivery that is already waiting never starts after shutdown
+            // or drain was requested.
+            let delivery = tokio::select! {
+                biased;
+                _ = state.wait_for(|state| *state != WorkerState::Running) => break,
+                delivery = consumer.next() => delivery,
+            };
+            match delivery {
+                Some(Ok(delivery)) => {
+                    handle_delivery(delivery, stage, &backend, &sending_channel, &config, &state).await;
+                },
+                Some(Err(e)) => {
+                    health.loop_ended(format!("consumer:{}", name));
+                    return Err(format!("error in consumer {}: {}", name, e).into());
+                },
+                None => {
+                    health.loop_ended(format!("consumer:{}", name));
+                    return Err(format!("consumer {} was cancelled by the broker", name).into());
+                },
+            }
+        }
+
+        // Stop deliveries and hand back the ones the broker already sent.
+        receiving_channel.basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default()).await.unwrap();
+        while let Some(Ok(delivery)) = consumer.next().await {
+            delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await.expect("nack");
+        }
+        tracing::info!(consumer = %name, "Stopped consuming tasks");
+    }
+
+    for channel in [receiving_channel, sending_channel] {
+        if let Err(e) = channel.close(200, "Worker shutting down").await {
+            tracing::warn!(consumer = %name, error = ?e, "Failed to close channel");
+        }
+    }
+    if let Err(e) = conn.close(200, "Worker shutting down").await {
+        tracing::warn!(consumer = %name, error = ?e, "Failed to close connection");
+    }
+    Ok(())
+}
+
+/// Resolves once shutdown was requested and the grace period for in-flight tasks has passed.
+async fn shutdown_deadline(mut state: watch::Receiver<WorkerState>, grace_period: Duration) {
+    if state.wait_for(|state| *state == WorkerState::ShuttingDown).await.is_ok() {
+        tokio::time::sleep(grace_period).await;
+    }
+}
+
+async fn handle_delivery(
+    delivery: Delivery,
+    stage: Stage,
+    backend: &ConsumerBackend,
+    sending_channel: &lapin::Channel,
+    config: &WorkerConfig,
+    state: &watch::Receiver<WorkerState>,
+) {
+    let queues = &config.queues;
+    metrics::observe_queue_latency(queues.pending(stage), &delivery.properties);
+
+    let task = match serde_json::from_slice::<VideoStylizerTaskInQueue>(&delivery.data) {
+        Ok(task) => task,
+        Err(e) => {
+            tracing::error!(queue = queues.pending(stage), error = ?e, "Failed to deserialize task");
+            delivery.ack(BasicAckOptions::default()).await.expect("ack");
+            return;
+        },
+    };
+
+    let span = tracing::info_span!(
+        "process_task",
+        task_id = %task.task_id,
+        stage = stage.as_str(),
+        backend = %backend.name,
+    );
+    telemetry::set_parent_from_headers(&span, &delivery.properties);
+
+    let task_id = task.task_id.clone();
+    async {
+        tracing::info!(attempts = task.attempts, "Processing task");
+        let in_flight = metrics::TASKS_IN_FLIGHT.with_label_values(&[stage.as_str()]);
+        in_flight.inc();
+        let outcome = tokio::select! {
+            outcome = process(stage, backend, task, config) => Some(outcome),
+            _ = shutdown_deadline(state.clone(), config.shutdown_timeout) => None,
+        };
+        in_flight.dec();
+        remove_file(preprocessed_source(config, &task_id)).await;
+
+        let published = match outcome {
+            Some(Outcome::Next(task)) => {
+                // Let the bot record the intermediate artifact before handing the task on.
+                let next_stage = task.next_stage().unwrap();
+                tracing::info!(next_stage = next_stage.as_str(), "Stage finished");
+                match publish(sending_channel, &queues.completed, &task).await {
+                    Ok(()) => publish(sending_channel, queues.pending(next_stage), &task).await,
+                    Err(e) => Err(e),
+                }
+            },
+            Some(Outcome::Done(task)) => {
+                tracing::info!(status = %task.status, "Task finished");
+                publish(sending_channel, &queues.completed, &task).await
+            },
+            Some(Outcome::Retry(task)) => {
+                publish_retry(sending_channel, queues, stage, &task, config.retry_delay).await
+            },
+            Some(Outcome::DeadLetter(task)) => {
+                // Only telling the bot decides the delivery's fate, so a failed publish never runs
+                // the stage again and dead-letters the task twice. The copy for admins is a
+                // best effort; the error is also stored with the task.
+                let published = publish(sending_channel, &queues.completed, &task).await;
+                if published.is_ok() {
+                    if let Err(e) = publish(sending_channel, &queues.dead_letter, &task).await {
+                        tracing::error!(error = ?e, "Failed to dead-letter task");
+                    }
+                }
+                published
+            },
+            None => {
+                tracing::warn!("Shutdown deadline passed, returning task to the queue");
+                delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await.expect("nack");
+                return;
+            },
+        };
+
+        match published {
+            Ok(()) => delivery.ack(BasicAckOptions::default()).await.expect("ack"),
+            Err(e) => {
+                // Running the stage again is preferred over losing the task.
+                tracing::error!(error = ?e, "Failed to publish task, returning it to the queue");
+                delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await.expect("nack");
+            },
+        }
+    }.instrument(span).await;
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    println!("args: {:?}", args);
-
-    let mut threads = Vec::with_capacity(args.backend.len());
-    for backend in args.backend {
-        println!("Starting worker for backend: {}", backend);
-        let amqp_uri = args.amqp_uri.clone();
-        let handle = tokio::spawn(async move {
-            let options = ConnectionProperties::default()
-                .with_executor(tokio_executor_trait::Tokio::current())
-                .with_reactor(tokio_reactor_trait::Tokio);
-            let conn = Connection::connect(&amqp_uri, options).await.unwrap();
-
-            let sending_channel = conn.create_channel().await.unwrap();
-            let receiving_channel = conn.create_channel().await.unwrap();
-
-            let options = QueueDeclareOptions {
-                durable: true,
-                auto_delete: true,
-                ..Default::default()
-            };
-            sending_channel.queue_declare(
-                "completedVideoStylizerTasks", options, FieldTable::default()
-            ).await.unwrap();
-
-            receiving_channel.queue_declare(
-                "pendingVideoStylizerTasks", options, FieldTable::default()
-            ).await.unwrap();
-
-            let mut consumer = receiving_channel.basic_consume(
-                "pendingVideoStylizerTasks",
-                "worker",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
-            ).await.unwrap();
-
-            let http_client = reqwest::Client::new();
-
-            while let Some(delivery) = consumer.next().await {
-                let delivery = delivery.expect("error in consumer");
-                let _task = serde_json::from_slice::<VideoStylizerTaskInQueue>(
-                    &delivery.data
-                );
-
-                if let Ok(task) = _task {
-                    match http_client.post(&backend)
-                        .json(&VideoStylizerRequestBody {
-                            videoname: task.src_video_url.clone(),
-                            video_prompt: task.video_prompt.clone().unwrap_or_else(|| "".to_owned()),
-                            style_prompt: task.style_prompt.clone(),
-                            n_prompt: task.negative_prompt.clone().unwrap_or_else(|| "".to_owned()),
-                            max_keyframe: if task.max_keyframes.is_none() { -1} else { task.max_keyframes.unwrap() as i64 },
-                            seed: task.seed,
-                        })
-                        .send()
-                        .await {
-                        Ok(rsp) => {
-                            let rsp_body = rsp.json::<VideoStylizerResponseBody>().await;
-                            let (status, result) = match rsp_body {
-                                Ok(rsp_body) => ("completed".to_owned(), rsp_body.output_path.clone()),
-                                Err(e) => ("failed".to_owned(), format!("{:?}", e)),
-                            };
-                            let task = task.with_result(status, result);
-                            let payload = serde_json::to_vec(&task).unwrap();
-                            sending_channel.basic_publish(
-                                "",
-                                "completedVideoStylizerTasks",
-                                BasicPublishOptions::default(),
-                                &payload,
-                                BasicProperties::default(),
-                            ).await.unwrap().await.unwrap();
-                            delivery.ack(BasicAckOptions::default()).await.expect("ack");
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to send task to backend: {:?}", e);
-                            delivery.nack(BasicNackOptions::default()).await.expect("nack");
-                        }
-                    }
-                } else {
-                    eprintln!("Failed to deserialize task.");
-                    delivery.ack(BasicAckOptions::default()).await.expect("ack");
+    let _telemetry = telemetry::init(&args.log, "omni-worker").unwrap();
+    tracing::info!(?args, "Starting worker");
+
+    tokio::fs::create_dir_all(&args.work_dir).await.unwrap();
+
+    let (state_sender, state) = watch::channel(WorkerState::Running);
+    tokio::spawn(handle_signals(state_sender));
+
+    let health = Health::default();
+    health.add_check("state", {
+        let state = state.clone();
+        move || {
+            let current = *state.borrow();
+            async move {
+                match current {
+                    WorkerState::Running => Ok(()),
+                    state => Err(format!("{:?}", state).into()),
                 }
             }
+        }
+    });
+    let metrics_addr = args.metrics_addr;
+    let metrics_health = health.clone();
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_addr, metrics_health).await {
+            tracing::error!(error = ?e, "Metrics server failed");
+        }
+    });
+
+    let config_file: ConfigFile = match &args.config {
+        Some(path) => config::load_toml(path).await.unwrap(),
+        None => ConfigFile::default(),
+    };
+
+    let config = Arc::new(WorkerConfig {
+        ffmpeg: args.ffmpeg,
+        work_dir: args.work_dir,
+        preview_duration: args.preview_duration,
+        preview_frames: args.preview_frames,
+        upscale_factor: args.upscale_factor,
+        interpolation_multiplier: args.interpolation_multiplier,
+        max_retries: args.max_retries,
+        retry_delay: Duration::from_secs(args.retry_delay),
+        shutdown_timeout: Duration::from_secs(args.shutdown_timeout),
+        queues: config_file.queues,
+    });
+
+    let backend_options = BackendOptions {
+        request_timeout: Duration::from_secs(args.backend_request_timeout),
+        read_timeout: Duration::from_secs(args.backend_read_timeout),
+        poll_interval: Duration::from_secs(args.backend_poll_interval),
+        poll_deadline: Duration::from_secs(args.backend_poll_deadline),
+    };
+
+    let pools = [
+        (Stage::Stylize, args.backend),
+        (Stage::Upscale, args.upscale_backend),
+        (Stage::Interpolate, args.interpolate_backend),
+    ];
+
+    let mut backend_configs = Vec::new();
+    for (stage, backends) in pools {
+        for url in backends {
+            let mut backend_config = BackendConfig::new(url, stage);
+            backend_config.token = args.backend_token.clone();
+            backend_config.api_key = args.backend_api_key.clone();
+            backend_config.api_key_header = args.backend_api_key_header.clone();
+            backend_config.ca_cert = args.backend_ca_cert.clone();
+            backend_config.client_cert = args.backend_client_cert.clone();
+            backend_config.client_key = args.backend_client_key.clone();
+            backend_config.native_preprocess = args.native_preprocess;
+            backend_configs.push(backend_config);
+        }
+    }
+
+    backend_configs.extend(config_file.backends);
+
+    let connect_timeout = Duration::from_secs(args.backend_connect_timeout);
+
+    let mut threads = Vec::new();
+    for backend_config in backend_configs {
+        tracing::info!(stage = backend_config.stage.as_str(), backend = %backend_config.url, "Starting consumer");
+        let http_client = backend_config.http_client(connect_timeout).await.unwrap();
+        let backend = backend::from_url(&backend_config.url, http_client, &backend_options).unwrap();
+        health.add_check(format!("backend:{}", backend_config.url), {
+            let backend = backend.clone();
+            move || {
+                let backend = backend.clone();
+                async move { backend.health().await }
+            }
         });
+        let backend = ConsumerBackend {
+            backend,
+            name: backend_config.url,
+            native_preprocess: backend_config.native_preprocess,
+        };
+        let handle = tokio::spawn(consume(
+            backend_config.stage, backend, args.amqp.clone(), config.clone(), health.clone(), state.clone()
+        ));
         threads.push(handle);
     }
 
-    for handle in threads {
-        handle.await.unwrap();
+    // A consumer that stopped leaves the worker unable to take its tasks; exit so the
+    // container gets restarted instead of staying up deaf.
+    let consumers = threads.into_iter().map(|handle| async move { handle.await? });
+    if let Err(e) = futures::future::try_join_all(consumers).await {
+        tracing::error!(error = ?e, "Consumer stopped, exiting");
+        std::process::exit(1);
     }
+    tracing::info!("Worker stopped");
 }
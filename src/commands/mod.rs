@@ -1,3 +1,4 @@
+pub mod settings;
 pub mod video_to_video;
 
 use crate::{Context, Error};
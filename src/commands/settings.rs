@@ -0,0 +1,29 @@
+use crate::{Context, Error};
+use mongodb::{bson::{doc, DateTime}, options::UpdateOptions};
+
+#[poise::command(
+    slash_command,
+    category = "Settings",
+    description_localized("en-US", "Choose whether results are also sent to your direct messages."),
+    description_localized("zh-CN", "选择是否同时通过私信接收结果。"),
+)]
+pub async fn notify_by_dm(
+    ctx: Context<'_>,
+    #[description = "Send results to your direct messages too."]
+    enabled: bool,
+) -> Result<(), Error> {
+    ctx.data().user_preference_collection.update_one(
+        doc! {"user_id": ctx.author().id.0 as i64},
+        doc! {"$set": {"notify_by_dm": enabled, "updated_at": DateTime::now()}},
+        UpdateOptions::builder().upsert(true).build(),
+    ).await?;
+
+    let response = if enabled {
+        "> Results will also be sent to your direct messages."
+    } else {
+        "> Results will only be posted where you used the command."
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+
+    Ok(())
+}
@@ -1,7 +1,13 @@
-use crate::{Context, Error, schemas::{VideoStylizerTaskCreation, VideoStylizerTaskInDB}};
-use lapin::{options::BasicPublishOptions, BasicProperties};
+use crate::{Context, Error, UserData, config::BotSettings, embeds, error_code::ErrorCode, outbox, schemas::{AudioSource, OutputFormat, Stage, VideoStylizerGroupInDB, VideoStylizerTaskCreation, VideoStylizerTaskInDB}};
 use poise::{serenity_prelude as serenity, ChoiceParameter};
-use mongodb::results::InsertOneResult;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime, Document}, options::UpdateOptions, results::InsertOneResult, Collection};
+
+/// Custom ID prefix of the button that renders the full video of a preview task.
+pub const RENDER_FULL_BUTTON_PREFIX: &str = "video_stylizer:render_full:";
+/// Custom ID prefix of the button that upscales a finished video.
+pub const UPSCALE_BUTTON_PREFIX: &str = "video_stylizer:upscale:";
+/// Custom ID prefix of the button that interpolates frames of a finished video.
+pub const SMOOTH_BUTTON_PREFIX: &str = "video_stylizer:smooth:";
 
 #[derive(ChoiceParameter)]
 enum StyleChoice {
@@ -21,13 +27,71 @@ enum StyleChoice {
     ClayLook,
 }
 
+impl StyleChoice {
+    /// The prompt of this style, unless a preset in the bot's config overrides it.
+    fn prompt(&self, settings: &BotSettings) -> String {
+        if let Some(prompt) = settings.style_presets.get(self.name()) {
+            return prompt.clone();
+        }
+
+        let prompt = match self {
+            StyleChoice::ChinesePainting => "<chinese painting>",
+            StyleChoice::OilPainting => "<oil painting>",
+            StyleChoice::Cyberpunk => "<cyberpunk>",
+            StyleChoice::Cartoon3D => "<3d cartoon>",
+            StyleChoice::JapaneseAnimation => "<japanese animation>",
+            StyleChoice::PaperArt => "<paper art>",
+            StyleChoice::ClayLook => "<clay look>",
+        };
+        prompt.to_owned()
+    }
+}
+
+#[derive(ChoiceParameter)]
+enum OutputFormatChoice {
+    #[name = "MP4"]
+    Mp4,
+    #[name = "WebM"]
+    WebM,
+    #[name = "GIF"]
+    Gif,
+}
+
+impl From<OutputFormatChoice> for OutputFormat {
+    fn from(choice: OutputFormatChoice) -> Self {
+        match choice {
+            OutputFormatChoice::Mp4 => OutputFormat::Mp4,
+            OutputFormatChoice::WebM => OutputFormat::WebM,
+            OutputFormatChoice::Gif => OutputFormat::Gif,
+        }
+    }
+}
+
+fn file_too_large_response(settings: &BotSettings) -> String {
+    format!("> File size too large. Max file size is **{}MB**.", settings.max_file_size / 1024 / 1024)
+}
+
+/// Whether a worker pool runs `stage`, so tasks with it get processed.
+pub fn stage_available(settings: &BotSettings, stage: Stage) -> bool {
+    stage == Stage::Stylize || settings.optional_stages.contains(&stage)
+}
+
+fn stage_unavailable_response(stage: Stage) -> String {
+    let name = match stage {
+        Stage::Stylize => "Stylization",
+        Stage::Upscale => "Upscaling",
+        Stage::Interpolate => "Frame interpolation",
+    };
+    format!("> {} is not available right now.", name)
+}
+
 #[poise::command(
-    prefix_command,
     slash_command,
     category = "Video to Video",
     description_localized("en-US", "Stylize a video with a style prompt."),
     description_localized("zh-CN", "视频风格化。"),
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn video_stylizer(
     ctx: Context<'_>,
     #[description = "Video to stylize."]
@@ -43,73 +107,577 @@ pub async fn video_stylizer(
     max_keyframes: Option<u64>,
     #[description = "Seed for the random number generator."]
     seed: Option<u64>,
+    #[description = "Start time of the clip to stylize, in seconds."]
+    #[min = 0]
+    start: Option<f64>,
+    #[description = "End time of the clip to stylize, in seconds."]
+    #[min = 0]
+    end: Option<f64>,
+    #[description = "Maximum width of the output video."]
+    #[min = 64]
+    max_width: Option<u64>,
+    #[description = "Maximum height of the output video."]
+    #[min = 64]
+    max_height: Option<u64>,
+    #[description = "Frame rate of the output video."]
+    #[min = 1]
+    #[max = 60]
+    fps: Option<f64>,
+    #[description = "Render a few still frames first instead of the full video."]
+    preview: Option<bool>,
+    #[description = "Second video to stylize with the same settings."]
+    video_2: Option<serenity::Attachment>,
+    #[description = "Third video to stylize with the same settings."]
+    video_3: Option<serenity::Attachment>,
+    #[description = "Keep the audio track of the original video. Defaults to true."]
+    keep_audio: Option<bool>,
+    #[description = "File format of the stylized video."]
+    output_format: Option<OutputFormatChoice>,
+    #[description = "Upscale the stylized video."]
+    upscale: Option<bool>,
+    #[description = "Interpolate frames for smoother motion."]
+    smooth: Option<bool>,
 ) -> Result<(), Error> {
-    let video_url = video.url;
     let seed = seed.unwrap_or_else(|| rand::random::<u16>() as u64);
+    let preview = preview.unwrap_or(false);
+    let keep_audio = keep_audio.unwrap_or(true);
+    let output_format = output_format.map(OutputFormat::from).unwrap_or_default();
 
-    if video.size > 64 * 1024 * 1024 {
-        let response = "> File size too large. Max file size is **64MB**.".to_owned();
+    let mut stages = vec![Stage::Stylize];
+    if upscale.unwrap_or(false) {
+        stages.push(Stage::Upscale);
+    }
+    if smooth.unwrap_or(false) {
+        stages.push(Stage::Interpolate);
+    }
+
+    let videos: Vec<_> = [Some(video), video_2, video_3].into_iter().flatten().collect();
+    let settings = ctx.data().settings.clone();
+    if videos.iter().any(|video| video.size > settings.max_file_size) {
+        let response = file_too_large_response(&settings);
         ctx.say(response).await?;
         return Ok(());
     }
 
-    let style_prompt = match style_prompt {
-        StyleChoice::ChinesePainting => "<chinese painting>",
-        StyleChoice::OilPainting => "<oil painting>",
-        StyleChoice::Cyberpunk => "<cyberpunk>",
-        StyleChoice::Cartoon3D => "<3d cartoon>",
-        StyleChoice::JapaneseAnimation => "<japanese animation>",
-        StyleChoice::PaperArt => "<paper art>",
-        StyleChoice::ClayLook => "<clay look>",
-    };
-    let style_prompt = style_prompt.to_owned();
+    if let Some(stage) = stages.iter().find(|stage| !stage_available(&settings, **stage)) {
+        let response = stage_unavailable_response(*stage);
+        ctx.say(response).await?;
+        return Ok(());
+    }
+
+    if preview && videos.len() > 1 {
+        let response = "> Preview is only available for a single video.".to_owned();
+        ctx.say(response).await?;
+        return Ok(());
+    }
+
+    if let (Some(start), Some(end)) = (start, end) {
+        if end <= start {
+            let response = "> End time must be after start time.".to_owned();
+            ctx.say(response).await?;
+            return Ok(());
+        }
+    }
+
+    let tasks: Vec<_> = videos.into_iter().map(|video| VideoStylizerTaskCreation {
+        user_id: ctx.author().id.0,
+        channel_id: ctx.channel_id().0,
+        guild_id: ctx.guild_id().map(|id| id.0),
+        src_video_url: video.url,
+        video_prompt: video_prompt.clone(),
+        style_prompt: style_prompt.prompt(&settings),
+        style: Some(style_prompt.name().to_owned()),
+        negative_prompt: negative_prompt.clone(),
+        max_keyframes,
+        seed,
+        start,
+        end,
+        max_width,
+        max_height,
+        fps,
+        preview,
+        group_id: None,
+        keep_audio,
+        audio: None,
+        output_format,
+        stages: stages.clone(),
+        locale: ctx.locale().map(str::to_owned),
+    }).collect();
+
+    if tasks.len() > 1 {
+        return submit_group(ctx, "batch", tasks, false).await;
+    }
+    submit_task(ctx, tasks.into_iter().next().unwrap()).await
+}
+
+/// Text command form of [`video_stylizer`] with the options it had before trimming, batches
+/// and pipelines were added. Poise's argument parser doesn't scale to the full slash options,
+/// so those stay slash-only.
+#[poise::command(
+    prefix_command,
+    rename = "video_stylizer",
+    hide_in_help,
+    category = "Video to Video",
+)]
+pub async fn video_stylizer_prefix(
+    ctx: Context<'_>,
+    video: serenity::Attachment,
+    style_prompt: StyleChoice,
+    video_prompt: Option<String>,
+    negative_prompt: Option<String>,
+    max_keyframes: Option<u64>,
+    seed: Option<u64>,
+) -> Result<(), Error> {
+    let seed = seed.unwrap_or_else(|| rand::random::<u16>() as u64);
+
+    let settings = ctx.data().settings.clone();
+    if video.size > settings.max_file_size {
+        let response = file_too_large_response(&settings);
+        ctx.say(response).await?;
+        return Ok(());
+    }
 
     let task = VideoStylizerTaskCreation {
         user_id: ctx.author().id.0,
         channel_id: ctx.channel_id().0,
-        src_video_url: video_url,
+        guild_id: ctx.guild_id().map(|id| id.0),
+        src_video_url: video.url,
         video_prompt,
-        style_prompt,
+        style_prompt: style_prompt.prompt(&settings),
+        style: Some(style_prompt.name().to_owned()),
         negative_prompt,
         max_keyframes,
         seed,
+        start: None,
+        end: None,
+        max_width: None,
+        max_height: None,
+        fps: None,
+        preview: false,
+        group_id: None,
+        keep_audio: true,
+        audio: None,
+        output_format: OutputFormat::default(),
+        stages: vec![Stage::Stylize],
+        locale: ctx.locale().map(str::to_owned),
     };
 
-    let task_id = {
-        let col = ctx.data().video_stylizer_task_collection.clone();
+    submit_task(ctx, task).await
+}
+
+/// Creates a single task, replies with its embed and publishes it.
+async fn submit_task(ctx: Context<'_>, task: VideoStylizerTaskCreation) -> Result<(), Error> {
+    let _in_flight = ctx.data().in_flight.enter();
+
+    let task_id = match insert_task(ctx.data(), &task).await {
+        Ok(task_id) => Some(task_id),
+        Err(err) => {
+            let response = creation_failed_response(ctx.locale(), &err);
+            ctx.say(response).await?;
+            None
+        }
+    };
+
+    if let Some(task_id) = task_id {
+        let task_in_db = VideoStylizerTaskInDB::from(task.clone());
+        let reply = ctx.send(|m| m.embed(|e| {
+            embeds::task(e, &task_id, &task_in_db);
+            embeds::author(e, ctx.author())
+        })).await?;
+        let placeholder = reply.message().await.map(|message| message.id);
+        record_placeholder(&ctx.data().video_stylizer_task_collection, &task_id, placeholder).await;
+
+        publish_task(ctx.data(), task, &task_id).await;
+    };
+
+    Ok(())
+}
+
+#[poise::command(
+    slash_command,
+    category = "Video to Video",
+    description_localized("en-US", "Stylize a video with several styles and compare the results."),
+    description_localized("zh-CN", "使用多种风格处理视频并比较结果。"),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn video_style_compare(
+    ctx: Context<'_>,
+    #[description = "Video to stylize."]
+    video: serenity::Attachment,
+    #[description = "First style to compare."]
+    style_1: StyleChoice,
+    #[description = "Second style to compare."]
+    style_2: StyleChoice,
+    #[description = "Third style to compare."]
+    style_3: Option<StyleChoice>,
+    #[description = "Fourth style to compare."]
+    style_4: Option<StyleChoice>,
+    #[description = "Video prompt."]
+    video_prompt: Option<String>,
+    #[description = "Negative prompt to apply to the video."]
+    negative_prompt: Option<String>,
+    #[description = "Maximum number of keyframes."]
+    #[min = 2]
+    max_keyframes: Option<u64>,
+    #[description = "Seed for the random number generator."]
+    seed: Option<u64>,
+    #[description = "Also render the results side by side in a single video."]
+    grid: Option<bool>,
+    #[description = "Keep the audio track of the original video. Defaults to true."]
+    keep_audio: Option<bool>,
+    #[description = "File format of the stylized videos."]
+    output_format: Option<OutputFormatChoice>,
+) -> Result<(), Error> {
+    let seed = seed.unwrap_or_else(|| rand::random::<u16>() as u64);
+
+    let settings = ctx.data().settings.clone();
+    if video.size > settings.max_file_size {
+        let response = file_too_large_response(&settings);
+        ctx.say(response).await?;
+        return Ok(());
+    }
+
+    let output_format = output_format.map(OutputFormat::from).unwrap_or_default();
+
+    let styles = [Some(style_1), Some(style_2), style_3, style_4];
+    let tasks = styles.into_iter().flatten().map(|style| VideoStylizerTaskCreation {
+        user_id: ctx.author().id.0,
+        channel_id: ctx.channel_id().0,
+        guild_id: ctx.guild_id().map(|id| id.0),
+        src_video_url: video.url.clone(),
+        video_prompt: video_prompt.clone(),
+        style_prompt: style.prompt(&settings),
+        style: Some(style.name().to_owned()),
+        negative_prompt: negative_prompt.clone(),
+        max_keyframes,
+        seed,
+        start: None,
+        end: None,
+        max_width: None,
+        max_height: None,
+        fps: None,
+        preview: false,
+        group_id: None,
+        keep_audio: keep_audio.unwrap_or(true),
+        audio: None,
+        output_format,
+        stages: vec![Stage::Stylize],
+        locale: ctx.locale().map(str::to_owned),
+    }).collect();
+
+    submit_group(ctx, "compare", tasks, grid.unwrap_or(false)).await
+}
 
-        let task_in_db: VideoStylizerTaskInDB = task.clone().into();
-        match col.insert_one(task_in_db, None).await {
-            Ok(InsertOneResult{ inserted_id, .. }) => {
-                Some(inserted_id.as_object_id().unwrap().to_hex())
-            },
+/// Creates a group for `tasks` so the bot can post a single reply once every member is done.
+async fn submit_group(
+    ctx: Context<'_>,
+    kind: &str,
+    mut tasks: Vec<VideoStylizerTaskCreation>,
+    grid: bool,
+) -> Result<(), Error> {
+    let _in_flight = ctx.data().in_flight.enter();
+    let group_col = ctx.data().video_stylizer_group_collection.clone();
+
+    let mut group = VideoStylizerGroupInDB {
+        user_id: ctx.author().id.0,
+        channel_id: ctx.channel_id().0,
+        kind: kind.to_owned(),
+        task_ids: Vec::new(),
+        grid,
+        status: "pending".to_owned(),
+        placeholder_message_id: None,
+        created_at: DateTime::now(),
+        updated_at: DateTime::now(),
+    };
+    let group_id = match group_col.insert_one(group.clone(), None).await {
+        Ok(InsertOneResult { inserted_id, .. }) => inserted_id.as_object_id().unwrap(),
+        Err(err) => {
+            let response = creation_failed_response(ctx.locale(), &err);
+            ctx.say(response).await?;
+            return Ok(());
+        }
+    };
+
+    let mut task_ids = Vec::with_capacity(tasks.len());
+    for task in tasks.iter_mut() {
+        task.group_id = Some(group_id.to_hex());
+        match insert_task(ctx.data(), task).await {
+            Ok(task_id) => task_ids.push(task_id),
             Err(err) => {
-                let response = format!("> Failed to create video stylization task. Error: {:?}", err);
+                let response = creation_failed_response(ctx.locale(), &err);
                 ctx.say(response).await?;
-                None
+                return Ok(());
             }
         }
-    };
+    }
 
-    if task_id.is_some() {
-        let task_id = task_id.unwrap();
-        let response = format!(
-            "> We are working on your video. We will notify you when it is ready. Task ID: **{task_id}.**"
-        );
-        ctx.say(response).await?;
+    group_col.update_one(
+        doc! {"_id": group_id},
+        doc! {"$set": {"task_ids": task_ids.clone(), "updated_at": DateTime::now()}},
+        None,
+    ).await?;
+
+    group.task_ids = task_ids.clone();
+    let members: Vec<VideoStylizerTaskInDB> = tasks.iter().cloned().map(Into::into).collect();
+    let members: Vec<_> = task_ids.iter().map(String::as_str).zip(members.iter()).collect();
+    let reply = ctx.send(|m| m.embed(|e| {
+        embeds::group(e, &group_id.to_hex(), &group, &members);
+        embeds::author(e, ctx.author())
+    })).await?;
+    let placeholder = reply.message().await.map(|message| message.id);
+    record_placeholder(&group_col, &group_id.to_hex(), placeholder).await;
+
+    for (task, task_id) in tasks.into_iter().zip(task_ids) {
+        publish_task(ctx.data(), task, &task_id).await;
+    }
+
+    Ok(())
+}
+
+/// Logs why a task could not be created and returns the friendly message for the user.
+fn creation_failed_response(locale: Option<&str>, err: &dyn std::fmt::Debug) -> String {
+    tracing::error!(error = ?err, "Failed to create video stylization task");
+    format!(
+        "> Failed to create video stylization task. {} (Error code: `{}`)",
+        ErrorCode::Internal.user_message(locale),
+        ErrorCode::Internal,
+    )
+}
 
-        let channel = ctx.data().video_stylizer_task_pending_channel.clone();
+/// Inserts a task with its outbox entry, returning its ID.
+async fn insert_task(data: &UserData, task: &VideoStylizerTaskCreation) -> Result<String, Error> {
+    let col = data.video_stylizer_task_collection.clone();
 
-        let payload = serde_json::to_vec(&task.with_task_id(task_id)).unwrap();
+    let mut task_in_db: VideoStylizerTaskInDB = task.clone().into();
+    task_in_db.outbox = Some(outbox::entry());
+    let InsertOneResult { inserted_id, .. } = col.insert_one(task_in_db, None).await?;
 
-        channel.basic_publish(
-            "",
-            "pendingVideoStylizerTasks",
-            BasicPublishOptions::default(),
-            &payload,
-            BasicProperties::default(),
-        ).await?.await?;
+    Ok(inserted_id.as_object_id().unwrap().to_hex())
+}
+
+/// Remembers the reply saying a task or group was created, so its results can reply to it. The
+/// results are still posted without it, so failures are only logged.
+async fn record_placeholder<T>(
+    collection: &Collection<T>,
+    id: &str,
+    placeholder: Result<serenity::MessageId, serenity::Error>,
+) {
+    let result: Result<(), Error> = async {
+        collection.update_one(
+            doc! {"_id": ObjectId::parse_str(id)?},
+            doc! {"$set": {"placeholder_message_id": placeholder?.0 as i64}},
+            None,
+        ).await?;
+        Ok(())
+    }.await;
+    if let Err(e) = result {
+        tracing::warn!(id, error = ?e, "Failed to record placeholder message");
+    }
+}
+
+/// Publishes a task inserted by [`insert_task`]. The user was already told the task was
+/// created, so a failed publish is left in the outbox to be retried when the bot starts.
+async fn publish_task(data: &UserData, task: VideoStylizerTaskCreation, task_id: &str) {
+    let result = outbox::relay(
+        &data.video_stylizer_task_collection,
+        &data.video_stylizer_task_pending_channel,
+        &data.settings.queues,
+        task_id,
+        task,
+    ).await;
+    if let Err(e) = result {
+        tracing::warn!(task_id, error = ?e, "Failed to publish task, leaving it in the outbox");
+    }
+}
+
+/// Task started from a button on a result.
+enum FollowUp {
+    Created(Box<VideoStylizerTaskCreation>, String),
+    /// The button was clicked before; repeat clicks show the task started then.
+    Existing(Box<VideoStylizerTaskInDB>, String),
+}
+
+/// Handles the buttons attached to results: "Render full video" on previews, and
+/// "Upscale"/"Smooth" on finished videos, which run a single extra stage on the result.
+pub async fn handle_component(
+    ctx: &serenity::Context,
+    component: &serenity::MessageComponentInteraction,
+    data: &UserData,
+) -> Result<(), Error> {
+    let custom_id = component.data.custom_id.as_str();
+    let (button, follow_up_stage, source_task_id) = if let Some(task_id) = custom_id.strip_prefix(RENDER_FULL_BUTTON_PREFIX) {
+        ("render_full", None, task_id)
+    } else if let Some(task_id) = custom_id.strip_prefix(UPSCALE_BUTTON_PREFIX) {
+        ("upscale", Some(Stage::Upscale), task_id)
+    } else if let Some(task_id) = custom_id.strip_prefix(SMOOTH_BUTTON_PREFIX) {
+        ("smooth", Some(Stage::Interpolate), task_id)
+    } else {
+        return Ok(());
     };
 
+    // Discord only waits 3 seconds for a response, less than the lookup and insert may take.
+    component.create_interaction_response(ctx, |r| {
+        r.kind(serenity::InteractionResponseType::DeferredChannelMessageWithSource)
+    }).await?;
+
+    let _in_flight = data.in_flight.enter();
+    let follow_up = follow_up_task(data, component, button, follow_up_stage, source_task_id).await;
+
+    let placeholder = component.edit_original_interaction_response(ctx, |d| match &follow_up {
+        Ok(FollowUp::Created(task, task_id)) => d.embed(|e| {
+            embeds::task(e, task_id, &VideoStylizerTaskInDB::from((**task).clone()));
+            embeds::author(e, &component.user)
+        }),
+        Ok(FollowUp::Existing(task, task_id)) => d.embed(|e| embeds::task(e, task_id, task)),
+        Err(response) => d.content(response),
+    }).await.map(|message| message.id);
+
+    match follow_up {
+        Ok(FollowUp::Created(task, task_id)) => {
+            record_placeholder(&data.video_stylizer_task_collection, &task_id, placeholder).await;
+            publish_task(data, *task, &task_id).await;
+        },
+        _ => {
+            placeholder?;
+        },
+    }
+
     Ok(())
 }
+
+/// Creates the task for a click on `button`, unless an earlier click already did.
+///
+/// The click first claims the button on the source task with a conditional update that records
+/// the new task's ID, and only then inserts the task with its outbox entry, so a click that lost
+/// the claim never leaves a task behind to be relayed. A claimed task that is missing because
+/// the bot stopped in between is inserted by the next click instead.
+async fn follow_up_task(
+    data: &UserData,
+    component: &serenity::MessageComponentInteraction,
+    button: &str,
+    follow_up_stage: Option<Stage>,
+    source_task_id: &str,
+) -> Result<FollowUp, String> {
+    let col = &data.video_stylizer_task_collection;
+    let locale = Some(component.locale.as_str());
+    let not_found = || "> The original task no longer exists.".to_owned();
+
+    if let Some(stage) = follow_up_stage.filter(|stage| !stage_available(&data.settings, *stage)) {
+        return Err(stage_unavailable_response(stage));
+    }
+
+    let source_id = ObjectId::parse_str(source_task_id).map_err(|_| not_found())?;
+    let source_task = col.find_one(doc! {"_id": source_id}, None).await
+        .map_err(|err| creation_failed_response(locale, &err))?
+        .ok_or_else(not_found)?;
+
+    let claimed = source_task.follow_ups.get(button).cloned();
+
+    // The raw output of the last backend stage, not the result, which may have been muxed or
+    // transcoded into a GIF or WebM.
+    let last_output = source_task.artifacts.last()
+        .map(|artifact| artifact.output_path.clone())
+        .or_else(|| source_task.result.clone());
+    let mut task: VideoStylizerTaskCreation = source_task.into();
+    task.user_id = component.user.id.0;
+    task.channel_id = component.channel_id.0;
+    task.guild_id = component.guild_id.map(|id| id.0);
+    task.preview = false;
+    task.group_id = None;
+    task.locale = Some(component.locale.clone());
+
+    if let Some(stage) = follow_up_stage {
+        // Backend outputs have no audio track, so the result keeps taking it from the source.
+        // Tasks stored before the audio source was recorded were cut to their own trim.
+        task.audio = task.audio.take().or_else(|| Some(AudioSource {
+            url: task.src_video_url.clone(),
+            start: task.start,
+            end: task.end,
+        }));
+        // The output is already trimmed and resized; only the extra stage runs on it.
+        task.src_video_url = last_output.unwrap_or_default();
+        task.stages = vec![stage];
+        task.start = None;
+        task.end = None;
+        task.max_width = None;
+        task.max_height = None;
+        task.fps = None;
+    }
+
+    let task_id = match claimed {
+        Some(task_id) => task_id,
+        None => {
+            let task_id = ObjectId::new().to_hex();
+            let field = format!("follow_ups.{}", button);
+            let mut filter = doc! {"_id": source_id};
+            filter.insert(field.clone(), doc! {"$exists": false});
+            let mut claim = Document::new();
+            claim.insert(field, task_id.clone());
+            let claimed = col.find_one_and_update(filter, doc! {"$set": claim}, None).await
+                .map_err(|err| creation_failed_response(locale, &err))?;
+            if claimed.is_some() {
+                return insert_follow_up(data, task, task_id, locale).await;
+            }
+
+            // Another click claimed the button first.
+            let source_task = col.find_one(doc! {"_id": source_id}, None).await
+                .map_err(|err| creation_failed_response(locale, &err))?
+                .ok_or_else(not_found)?;
+            source_task.follow_ups.get(button).cloned().ok_or_else(not_found)?
+        },
+    };
+
+    let Ok(id) = ObjectId::parse_str(&task_id) else {
+        return Err(not_found());
+    };
+    let existing = col.find_one(doc! {"_id": id}, None).await
+        .map_err(|err| creation_failed_response(locale, &err))?;
+    match existing {
+        Some(existing) => Ok(FollowUp::Existing(Box::new(existing), task_id)),
+        // The bot stopped between claiming the button and inserting the task.
+        None => insert_follow_up(data, task, task_id, locale).await,
+    }
+}
+
+/// Inserts a follow-up task under the ID its button claim recorded, unless a racing click
+/// inserted it already.
+async fn insert_follow_up(
+    data: &UserData,
+    task: VideoStylizerTaskCreation,
+    task_id: String,
+    locale: Option<&str>,
+) -> Result<FollowUp, String> {
+    let col = &data.video_stylizer_task_collection;
+    let inserted: Result<bool, Error> = async {
+        let mut task_in_db: VideoStylizerTaskInDB = task.clone().into();
+        task_in_db.outbox = Some(outbox::entry());
+        let result = col.update_one(
+            doc! {"_id": ObjectId::parse_str(&task_id)?},
+            doc! {"$setOnInsert": mongodb::bson::to_document(&task_in_db)?},
+            UpdateOptions::builder().upsert(true).build(),
+        ).await?;
+        Ok(result.upserted_id.is_some())
+    }.await;
+
+    match inserted {
+        Ok(true) => Ok(FollowUp::Created(Box::new(task), task_id)),
+        Ok(false) => existing_follow_up(col, &task_id, locale).await,
+        Err(err) => Err(creation_failed_response(locale, &err)),
+    }
+}
+
+async fn existing_follow_up(
+    col: &Collection<VideoStylizerTaskInDB>,
+    task_id: &str,
+    locale: Option<&str>,
+) -> Result<FollowUp, String> {
+    let task = match ObjectId::parse_str(task_id) {
+        Ok(id) => col.find_one(doc! {"_id": id}, None).await
+            .map_err(|err| creation_failed_response(locale, &err))?,
+        Err(_) => None,
+    };
+    match task {
+        Some(task) => Ok(FollowUp::Existing(Box::new(task), task_id.to_owned())),
+        None => Err("> The task started from this button no longer exists.".to_owned()),
+    }
+}
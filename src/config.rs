@@ -0,0 +1,60 @@
+use crate::{Error, amqp::QueueNames, schemas::Stage};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::HashMap, convert::Infallible, path::Path, str::FromStr};
+
+/// A credential that is never printed, so configs can be logged with `{:?}`.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+pub async fn load_toml<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let content = tokio::fs::read_to_string(path).await
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    let config = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+/// Runtime settings of the bot's commands, shared through [`crate::UserData`].
+#[derive(Clone, Debug)]
+pub struct BotSettings {
+    /// Largest attachment accepted by the commands, in bytes.
+    pub max_file_size: u64,
+    /// Prompts overriding the built-in ones, keyed by the style's display name.
+    pub style_presets: HashMap<String, String>,
+    pub queues: QueueNames,
+    /// Stages after stylization that a worker pool is configured for. Tasks with any other
+    /// stage would wait in its queue forever, so they are rejected.
+    pub optional_stages: Vec<Stage>,
+}
+
+impl Default for BotSettings {
+    fn default() -> Self {
+        Self {
+            max_file_size: 64 * 1024 * 1024,
+            style_presets: HashMap::new(),
+            queues: QueueNames::default(),
+            optional_stages: Vec::new(),
+        }
+    }
+}
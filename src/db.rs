@@ -1,18 +1,63 @@
-use crate::schemas::VideoStylizerTaskInDB;
-use mongodb::{Client, Collection, options::ClientOptions};
+use crate::schemas::{UserPreferencesInDB, VideoStylizerGroupInDB, VideoStylizerTaskInDB};
+use crate::Error;
+use mongodb::{Client, Collection, Database, bson::doc, options::ClientOptions};
+use serde::Deserialize;
 
-pub async fn setup_db(uri: String) -> (Collection<VideoStylizerTaskInDB>,) {
+/// Database and collection names, configurable in the bot's TOML config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DbNames {
+    pub database: String,
+    pub task_collection: String,
+    pub group_collection: String,
+    pub preference_collection: String,
+}
+
+impl Default for DbNames {
+    fn default() -> Self {
+        Self {
+            database: "OmniAI".to_owned(),
+            task_collection: "video_stylizer_task".to_owned(),
+            group_collection: "video_stylizer_group".to_owned(),
+            preference_collection: "user_preferences".to_owned(),
+        }
+    }
+}
+
+pub async fn setup_db(
+    uri: &str,
+    names: &DbNames,
+) -> (
+    Database,
+    Collection<VideoStylizerTaskInDB>,
+    Collection<VideoStylizerGroupInDB>,
+    Collection<UserPreferencesInDB>,
+) {
     let mut client_options = ClientOptions::parse(uri).await.unwrap();
 
     client_options.app_name = Some("OmniBot".to_string());
 
     let client = Client::with_options(client_options).unwrap();
 
-    let db = client.database("OmniAI");
+    let db = client.database(&names.database);
 
     let video_stylizer_task_collection = db.collection::<VideoStylizerTaskInDB>(
-        "video_stylizer_task"
+        &names.task_collection
+    );
+
+    let video_stylizer_group_collection = db.collection::<VideoStylizerGroupInDB>(
+        &names.group_collection
+    );
+
+    let user_preference_collection = db.collection::<UserPreferencesInDB>(
+        &names.preference_collection
     );
 
-    (video_stylizer_task_collection,)
+    (db, video_stylizer_task_collection, video_stylizer_group_collection, user_preference_collection)
+}
+
+/// Readiness check of the MongoDB deployment.
+pub async fn ping(db: &Database) -> Result<(), Error> {
+    db.run_command(doc! {"ping": 1}, None).await?;
+    Ok(())
 }
@@ -0,0 +1,142 @@
+//! Embeds describing tasks and groups, shared by every message that shows one.
+
+use crate::{error_code::ErrorCode, schemas::{Stage, VideoStylizerGroupInDB, VideoStylizerTaskInDB}};
+use mongodb::bson::DateTime;
+use poise::serenity_prelude::{Colour, CreateEmbed, User};
+
+/// Longest value Discord accepts in an embed field.
+const FIELD_VALUE_LIMIT: usize = 1024;
+
+/// Colour of an embed for a task or group in `status`.
+pub fn status_colour(status: &str) -> Colour {
+    match status {
+        "pending" => Colour::BLURPLE,
+        "processing" => Colour::GOLD,
+        "completed" => Colour::DARK_GREEN,
+        _ => Colour::RED,
+    }
+}
+
+fn truncate(value: &str, limit: usize) -> String {
+    if value.chars().count() <= limit {
+        return value.to_owned();
+    }
+    let mut truncated: String = value.chars().take(limit - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn format_duration(secs: i64) -> String {
+    match secs {
+        secs if secs >= 3600 => format!("{}h {}m {}s", secs / 3600, secs % 3600 / 60, secs % 60),
+        secs if secs >= 60 => format!("{}m {}s", secs / 60, secs % 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+/// Footer text with `id` and, once finished, how long it took since submission.
+fn footer_text(label: &str, id: &str, status: &str, created_at: DateTime, updated_at: DateTime) -> String {
+    if status == "completed" || status == "failed" {
+        let millis = updated_at.timestamp_millis() - created_at.timestamp_millis();
+        format!("{}: {} • Took {}", label, id, format_duration(millis.max(0) / 1000))
+    } else {
+        format!("{}: {}", label, id)
+    }
+}
+
+/// Shows `user` as the author of the embed.
+pub fn author<'a>(embed: &'a mut CreateEmbed, user: &User) -> &'a mut CreateEmbed {
+    embed.author(|a| a.name(&user.name).icon_url(user.face()))
+}
+
+/// Describes a task: its parameters as fields, coloured by its status, and its ID and duration
+/// in the footer.
+pub fn task<'a>(embed: &'a mut CreateEmbed, task_id: &str, task: &VideoStylizerTaskInDB) -> &'a mut CreateEmbed {
+    let title = if task.preview { "Video Stylization Preview" } else { "Video Stylization" };
+    embed.title(title).colour(status_colour(&task.status));
+
+    match task.status.as_str() {
+        "pending" | "processing" if task.preview => {
+            embed.description("We are rendering a preview of your video. We will notify you when it is ready.");
+        },
+        "pending" | "processing" => {
+            embed.description("We are working on your video. We will notify you when it is ready.");
+        },
+        "failed" => {
+            let code = task.error.as_ref().map_or(ErrorCode::Internal, |error| error.code);
+            embed.description(format!(
+                "Failed to stylize your video. {} (Error code: `{}`)",
+                code.user_message(task.locale.as_deref()),
+                code,
+            ));
+        },
+        _ => {},
+    }
+
+    if let Some(video_prompt) = &task.video_prompt {
+        embed.field("Video Prompt", truncate(video_prompt, FIELD_VALUE_LIMIT), false);
+    }
+    embed.field("Style Prompt", truncate(&task.style_prompt, FIELD_VALUE_LIMIT), false);
+    if let Some(negative_prompt) = &task.negative_prompt {
+        embed.field("Negative Prompt", truncate(negative_prompt, FIELD_VALUE_LIMIT), false);
+    }
+    if let Some(max_keyframes) = task.max_keyframes {
+        embed.field("Max Keyframes", max_keyframes, true);
+    }
+    embed.field("Seed", task.seed, true);
+    if task.start.is_some() || task.end.is_some() {
+        embed.field(
+            "Clip",
+            format!(
+                "{}s - {}",
+                task.start.unwrap_or(0.0),
+                task.end.map_or("end".to_owned(), |end| format!("{}s", end)),
+            ),
+            true,
+        );
+    }
+    if let Some(fps) = task.fps {
+        embed.field("FPS", fps, true);
+    }
+    if task.stages != [Stage::Stylize] {
+        let stages: Vec<_> = task.stages.iter().map(|stage| format!("{:?}", stage)).collect();
+        embed.field("Stages", stages.join(" > "), true);
+    }
+
+    embed.footer(|f| f.text(footer_text("Task ID", task_id, &task.status, task.created_at, task.updated_at)))
+}
+
+/// Describes a group with one field per member task, coloured by the group's status.
+pub fn group<'a>(
+    embed: &'a mut CreateEmbed,
+    group_id: &str,
+    group: &VideoStylizerGroupInDB,
+    tasks: &[(&str, &VideoStylizerTaskInDB)],
+) -> &'a mut CreateEmbed {
+    let title = if group.kind == "compare" { "Video Style Comparison" } else { "Batch Video Stylization" };
+    embed.title(title).colour(status_colour(&group.status));
+    if group.status == "pending" {
+        embed.description(format!(
+            "We are working on your {} videos. We will notify you when all of them are ready.",
+            tasks.len(),
+        ));
+    }
+
+    for (i, (task_id, task)) in tasks.iter().enumerate() {
+        let outcome = match task.status.as_str() {
+            "completed" => "Completed".to_owned(),
+            "failed" => {
+                let code = task.error.as_ref().map_or(ErrorCode::Internal, |error| error.code);
+                format!("Failed: {} (`{}`)", code.user_message(task.locale.as_deref()), code)
+            },
+            _ => "In progress".to_owned(),
+        };
+        embed.field(
+            format!("{}. Task ID: {}", i + 1, task_id),
+            truncate(&format!("{}\nStyle Prompt: {}", outcome, task.style_prompt), FIELD_VALUE_LIMIT),
+            false,
+        );
+    }
+
+    embed.footer(|f| f.text(footer_text("Group ID", group_id, &group.status, group.created_at, group.updated_at)))
+}
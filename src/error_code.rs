@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+/// Failure reasons shown to users. The internal detail of a failure travels separately in
+/// [`crate::schemas::TaskError`] and is only stored in the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InputInvalid,
+    InputTooLong,
+    NsfwBlocked,
+    BackendUnavailable,
+    BackendFailed,
+    MediaProcessingFailed,
+    Expired,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InputInvalid => "INPUT_INVALID",
+            ErrorCode::InputTooLong => "INPUT_TOO_LONG",
+            ErrorCode::NsfwBlocked => "NSFW_BLOCKED",
+            ErrorCode::BackendUnavailable => "BACKEND_UNAVAILABLE",
+            ErrorCode::BackendFailed => "BACKEND_FAILED",
+            ErrorCode::MediaProcessingFailed => "MEDIA_PROCESSING_FAILED",
+            ErrorCode::Expired => "EXPIRED",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+
+    /// Maps a `code` from a backend error body onto the catalogue.
+    pub fn from_backend_code(code: &str) -> Option<Self> {
+        match code.to_ascii_uppercase().as_str() {
+            "INPUT_INVALID" | "INVALID_INPUT" => Some(ErrorCode::InputInvalid),
+            "INPUT_TOO_LONG" | "VIDEO_TOO_LONG" => Some(ErrorCode::InputTooLong),
+            "NSFW_BLOCKED" | "NSFW" => Some(ErrorCode::NsfwBlocked),
+            _ => None,
+        }
+    }
+
+    /// Friendly explanation for users, in Discord's `zh-CN` locale or English otherwise.
+    pub fn user_message(&self, locale: Option<&str>) -> &'static str {
+        let zh = locale.is_some_and(|locale| locale.starts_with("zh"));
+        match (self, zh) {
+            (ErrorCode::InputInvalid, false) => "Your video could not be processed. Please check the file and the options you chose.",
+            (ErrorCode::InputInvalid, true) => "无法处理您的视频，请检查文件和所选参数。",
+            (ErrorCode::InputTooLong, false) => "Your video is too long. Please trim it and try again.",
+            (ErrorCode::InputTooLong, true) => "视频过长，请剪辑后重试。",
+            (ErrorCode::NsfwBlocked, false) => "Your video was blocked by the content filter.",
+            (ErrorCode::NsfwBlocked, true) => "您的视频未通过内容审核。",
+            (ErrorCode::BackendUnavailable, false) => "Our servers are busy right now. Please try again later.",
+            (ErrorCode::BackendUnavailable, true) => "服务器繁忙，请稍后重试。",
+            (ErrorCode::BackendFailed, false) => "Something went wrong while stylizing your video. Please try again.",
+            (ErrorCode::BackendFailed, true) => "视频风格化失败，请重试。",
+            (ErrorCode::MediaProcessingFailed, false) => "We could not convert your video. Please try another file or format.",
+            (ErrorCode::MediaProcessingFailed, true) => "视频转换失败，请尝试其他文件或格式。",
+            (ErrorCode::Expired, false) => "Your video took too long to process and was cancelled. Please try again.",
+            (ErrorCode::Expired, true) => "视频处理超时，任务已取消，请重试。",
+            (ErrorCode::Internal, false) => "An internal error occurred. Please try again later.",
+            (ErrorCode::Internal, true) => "发生内部错误，请稍后重试。",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorCode;
+
+    #[test]
+    fn maps_backend_codes_case_insensitively() {
+        assert_eq!(ErrorCode::from_backend_code("nsfw"), Some(ErrorCode::NsfwBlocked));
+        assert_eq!(ErrorCode::from_backend_code("VIDEO_TOO_LONG"), Some(ErrorCode::InputTooLong));
+        assert_eq!(ErrorCode::from_backend_code("invalid_input"), Some(ErrorCode::InputInvalid));
+    }
+
+    #[test]
+    fn leaves_unknown_backend_codes_unmapped() {
+        assert_eq!(ErrorCode::from_backend_code("OUT_OF_MEMORY"), None);
+        assert_eq!(ErrorCode::from_backend_code("INTERNAL"), None);
+        assert_eq!(ErrorCode::from_backend_code(""), None);
+    }
+
+    #[test]
+    fn uses_chinese_for_zh_locales() {
+        let message = ErrorCode::Internal.user_message(Some("zh-CN"));
+        assert_eq!(message, "发生内部错误，请稍后重试。");
+        assert_eq!(ErrorCode::Internal.user_message(Some("zh-TW")), message);
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        let message = "An internal error occurred. Please try again later.";
+        assert_eq!(ErrorCode::Internal.user_message(None), message);
+        assert_eq!(ErrorCode::Internal.user_message(Some("en-US")), message);
+        assert_eq!(ErrorCode::Internal.user_message(Some("fr")), message);
+    }
+
+    #[test]
+    fn displays_as_the_serialized_code() {
+        assert_eq!(ErrorCode::MediaProcessingFailed.to_string(), "MEDIA_PROCESSING_FAILED");
+        assert_eq!(serde_json::to_string(&ErrorCode::Expired).unwrap(), "\"EXPIRED\"");
+    }
+}
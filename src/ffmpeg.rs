@@ -0,0 +1,228 @@
+use crate::{Error, schemas::OutputFormat};
+use std::path::Path;
+use tokio::process::Command;
+
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessOptions {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub fps: Option<f64>,
+}
+
+impl PreprocessOptions {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_none()
+            && self.end.is_none()
+            && self.max_width.is_none()
+            && self.max_height.is_none()
+            && self.fps.is_none()
+    }
+
+    fn video_filters(&self) -> Option<String> {
+        let mut filters = Vec::with_capacity(2);
+
+        if self.max_width.is_some() || self.max_height.is_some() {
+            let width = self.max_width.map_or("iw".to_owned(), |w| format!("min(iw\\,{w})"));
+            let height = self.max_height.map_or("ih".to_owned(), |h| format!("min(ih\\,{h})"));
+            filters.push(format!(
+                "scale=w={width}:h={height}:force_original_aspect_ratio=decrease:force_divisible_by=2"
+            ));
+        }
+
+        if let Some(fps) = self.fps {
+            filters.push(format!("fps={fps}"));
+        }
+
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(","))
+        }
+    }
+}
+
+async fn run(ffmpeg: &str, args: Vec<String>) -> Result<(), Error> {
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(&args)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffmpeg exited with {}: {}", output.status, stderr.trim()).into());
+    }
+
+    Ok(())
+}
+
+/// Trims, downscales and resamples `input` (a local path or URL) into `output`.
+pub async fn preprocess(
+    ffmpeg: &str,
+    input: &str,
+    output: &Path,
+    options: &PreprocessOptions,
+) -> Result<(), Error> {
+    let mut args = Vec::with_capacity(16);
+
+    if let Some(start) = options.start {
+        args.extend(["-ss".to_owned(), start.to_string()]);
+    }
+    if let Some(end) = options.end {
+        args.extend(["-to".to_owned(), end.to_string()]);
+    }
+    args.extend(["-i".to_owned(), input.to_owned()]);
+
+    if let Some(filters) = options.video_filters() {
+        args.extend(["-vf".to_owned(), filters]);
+    }
+    args.extend(["-c:a".to_owned(), "copy".to_owned()]);
+    args.push(output.to_string_lossy().into_owned());
+
+    run(ffmpeg, args).await
+}
+
+/// Extracts `count` evenly spaced frames from the first `duration` seconds of `input`
+/// as PNG images, returning the paths of the frames that were written.
+pub async fn extract_frames(
+    ffmpeg: &str,
+    input: &str,
+    output_dir: &Path,
+    prefix: &str,
+    count: u64,
+    duration: f64,
+) -> Result<Vec<String>, Error> {
+    let pattern = output_dir.join(format!("{prefix}_%02d.png"));
+    let args = vec![
+        "-i".to_owned(),
+        input.to_owned(),
+        "-vf".to_owned(),
+        format!("fps={count}/{duration}"),
+        "-frames:v".to_owned(),
+        count.to_string(),
+        pattern.to_string_lossy().into_owned(),
+    ];
+
+    run(ffmpeg, args).await?;
+
+    let mut frames = Vec::with_capacity(count as usize);
+    for i in 1..=count {
+        let frame = output_dir.join(format!("{prefix}_{i:02}.png"));
+        if tokio::fs::try_exists(&frame).await? {
+            frames.push(frame.to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(frames)
+}
+
+/// Grabs the first frame of `input` (a local path or URL) as an image scaled to `width`.
+pub async fn thumbnail(ffmpeg: &str, input: &str, output: &Path, width: u64) -> Result<(), Error> {
+    let args = vec![
+        "-i".to_owned(),
+        input.to_owned(),
+        "-frames:v".to_owned(),
+        "1".to_owned(),
+        "-vf".to_owned(),
+        format!("scale={width}:-2"),
+        output.to_string_lossy().into_owned(),
+    ];
+
+    run(ffmpeg, args).await
+}
+
+/// Renders `inputs` side by side into a single video scaled to a common `height`.
+pub async fn hstack(
+    ffmpeg: &str,
+    inputs: &[String],
+    output: &Path,
+    height: u64,
+) -> Result<(), Error> {
+    let mut args = Vec::with_capacity(inputs.len() * 2 + 6);
+    let mut filters = Vec::with_capacity(inputs.len() + 1);
+    let mut labels = String::new();
+
+    for (i, input) in inputs.iter().enumerate() {
+        args.extend(["-i".to_owned(), input.clone()]);
+        filters.push(format!("[{i}:v]scale=-2:{height},setsar=1[v{i}]"));
+        labels.push_str(&format!("[v{i}]"));
+    }
+    filters.push(format!("{labels}hstack=inputs={}:shortest=1[v]", inputs.len()));
+
+    args.extend([
+        "-filter_complex".to_owned(),
+        filters.join(";"),
+        "-map".to_owned(),
+        "[v]".to_owned(),
+        output.to_string_lossy().into_owned(),
+    ]);
+
+    run(ffmpeg, args).await
+}
+
+/// Copies the video stream of `video` and muxes in the audio track of `audio`, which may be
+/// trimmed with `start`/`end` to line up with a clip that was cut from it.
+pub async fn mux_audio(
+    ffmpeg: &str,
+    video: &str,
+    audio: &str,
+    output: &Path,
+    start: Option<f64>,
+    end: Option<f64>,
+) -> Result<(), Error> {
+    let mut args = Vec::with_capacity(20);
+    args.extend(["-i".to_owned(), video.to_owned()]);
+
+    if let Some(start) = start {
+        args.extend(["-ss".to_owned(), start.to_string()]);
+    }
+    if let Some(end) = end {
+        args.extend(["-to".to_owned(), end.to_string()]);
+    }
+    args.extend(["-i".to_owned(), audio.to_owned()]);
+
+    args.extend([
+        "-map".to_owned(),
+        "0:v:0".to_owned(),
+        "-map".to_owned(),
+        "1:a:0?".to_owned(),
+        "-c:v".to_owned(),
+        "copy".to_owned(),
+        "-c:a".to_owned(),
+        "aac".to_owned(),
+        "-shortest".to_owned(),
+        output.to_string_lossy().into_owned(),
+    ]);
+
+    run(ffmpeg, args).await
+}
+
+/// Transcodes `input` into `format`, e.g. a looping GIF or a small WebM for chat embeds.
+pub async fn transcode(
+    ffmpeg: &str,
+    input: &str,
+    output: &Path,
+    format: OutputFormat,
+) -> Result<(), Error> {
+    let mut args = vec!["-i".to_owned(), input.to_owned()];
+
+    let codec_args: &[&str] = match format {
+        OutputFormat::Mp4 => &[
+            "-c:v", "libx264", "-pix_fmt", "yuv420p", "-c:a", "aac", "-movflags", "+faststart",
+        ],
+        OutputFormat::WebM => &["-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "35", "-c:a", "libopus"],
+        OutputFormat::Gif => &[
+            "-vf",
+            "fps=12,scale='min(iw,480)':-2:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse",
+            "-loop",
+            "0",
+        ],
+    };
+    args.extend(codec_args.iter().map(|arg| arg.to_string()));
+    args.push(output.to_string_lossy().into_owned());
+
+    run(ffmpeg, args).await
+}
@@ -0,0 +1,88 @@
+use crate::Error;
+use futures::{future::{BoxFuture, join_all}, FutureExt};
+use std::{collections::BTreeMap, future::Future, sync::{Arc, Mutex}, time::Duration};
+
+/// How long a single readiness check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+type CheckFn = Arc<dyn Fn() -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// Liveness and readiness of a binary, served on `/healthz` and `/readyz` by [`crate::metrics::serve`].
+///
+/// Liveness covers the long-running loops that must never end, such as AMQP consumers; readiness
+/// runs checks against the services the binary depends on.
+#[derive(Clone, Default)]
+pub struct Health {
+    loops: Arc<Mutex<BTreeMap<String, bool>>>,
+    checks: Arc<Mutex<Vec<(String, CheckFn)>>>,
+}
+
+/// Outcome of probing a [`Health`], with one line per loop or check.
+pub struct Report {
+    pub healthy: bool,
+    pub lines: Vec<String>,
+}
+
+impl Health {
+    /// Registers a loop as running.
+    pub fn loop_started(&self, name: impl Into<String>) {
+        self.loops.lock().unwrap().insert(name.into(), true);
+    }
+
+    /// Marks a loop as ended, which fails liveness until the process is restarted.
+    pub fn loop_ended(&self, name: impl Into<String>) {
+        self.loops.lock().unwrap().insert(name.into(), false);
+    }
+
+    pub fn add_check<F, Fut>(&self, name: impl Into<String>, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let check: CheckFn = Arc::new(move || check().boxed());
+        self.checks.lock().unwrap().push((name.into(), check));
+    }
+
+    pub fn liveness(&self) -> Report {
+        let loops = self.loops.lock().unwrap();
+        Report {
+            healthy: loops.values().all(|running| *running),
+            lines: loops.iter()
+                .map(|(name, running)| format!("{}: {}", name, if *running { "running" } else { "ended" }))
+                .collect(),
+        }
+    }
+
+    /// Runs all readiness checks concurrently.
+    pub async fn readiness(&self) -> Report {
+        let checks = self.checks.lock().unwrap().clone();
+        let results = join_all(checks.iter().map(|(_, check)| {
+            tokio::time::timeout(CHECK_TIMEOUT, check())
+        })).await;
+
+        let mut report = Report { healthy: true, lines: Vec::with_capacity(checks.len()) };
+        for ((name, _), result) in checks.iter().zip(results) {
+            match result {
+                Ok(Ok(())) => report.lines.push(format!("{}: ok", name)),
+                Ok(Err(e)) => {
+                    report.healthy = false;
+                    report.lines.push(format!("{}: failed: {}", name, e));
+                },
+                Err(_) => {
+                    report.healthy = false;
+                    report.lines.push(format!("{}: failed: timed out", name));
+                },
+            }
+        }
+        report
+    }
+}
+
+/// Readiness check of an AMQP channel.
+pub fn channel_connected(channel: &lapin::Channel) -> Result<(), Error> {
+    if channel.status().connected() {
+        Ok(())
+    } else {
+        Err(format!("channel is {:?}", channel.status().state()).into())
+    }
+}
@@ -1,7 +1,17 @@
 pub mod amqp;
+pub mod backend;
 pub mod commands;
+pub mod config;
 pub mod db;
+pub mod embeds;
+pub mod error_code;
+pub mod ffmpeg;
+pub mod health;
+pub mod metrics;
+pub mod outbox;
 pub mod schemas;
+pub mod shutdown;
+pub mod telemetry;
 
 use std::sync::Arc;
 use mongodb::Collection;
@@ -11,5 +21,10 @@ pub type Context<'a> = poise::Context<'a, UserData, Error>;
 
 pub struct UserData {
     pub video_stylizer_task_collection: Arc<Collection<schemas::VideoStylizerTaskInDB>>,
+    pub video_stylizer_group_collection: Arc<Collection<schemas::VideoStylizerGroupInDB>>,
+    pub user_preference_collection: Arc<Collection<schemas::UserPreferencesInDB>>,
     pub video_stylizer_task_pending_channel: Arc<lapin::Channel>,
+    pub settings: Arc<config::BotSettings>,
+    /// Task submissions in progress, which shutdown waits for.
+    pub in_flight: shutdown::InFlight,
 }
@@ -0,0 +1,114 @@
+use crate::{Error, health::{Health, Report}};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header, service::{make_service_fn, service_fn}};
+use lapin::BasicProperties;
+use prometheus::{
+    Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::LazyLock, time::{SystemTime, UNIX_EPOCH}};
+
+pub static TASKS_SUBMITTED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "omni_tasks_submitted_total",
+    "Tasks submitted by users.",
+    &["style", "guild"]
+).unwrap());
+
+pub static TASKS_FINISHED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "omni_tasks_finished_total",
+    "Tasks that completed or failed.",
+    &["status", "style", "guild"]
+).unwrap());
+
+pub static QUEUE_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "omni_queue_consume_latency_seconds",
+    "Time between publishing a task and consuming it, with one second resolution.",
+    &["queue"],
+    vec![1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0]
+).unwrap());
+
+pub static BACKEND_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "omni_backend_request_duration_seconds",
+    "Time backends took to run a stage, including polling.",
+    &["backend", "stage"],
+    vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0]
+).unwrap());
+
+pub static BACKEND_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "omni_backend_errors_total",
+    "Failed backend requests by error class.",
+    &["backend", "stage", "class"]
+).unwrap());
+
+pub static DISCORD_SEND_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "omni_discord_send_failures_total",
+    "Messages the bot could not deliver to Discord.",
+    &["kind"]
+).unwrap());
+
+pub static TASKS_IN_FLIGHT: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "omni_tasks_in_flight",
+    "Tasks a worker is currently processing.",
+    &["stage"]
+).unwrap());
+
+/// Label value of a task's guild, `dm` for direct messages.
+pub fn guild_label(guild_id: Option<u64>) -> String {
+    guild_id.map_or("dm".to_owned(), |id| id.to_string())
+}
+
+/// Label value of a task's style: its preset name, or `custom` so free-form prompts don't
+/// create a series each.
+pub fn style_label(style: Option<&str>) -> &str {
+    style.unwrap_or("custom")
+}
+
+/// Records how long a delivery waited in `queue`, if the publisher stamped it.
+pub fn observe_queue_latency(queue: &str, properties: &BasicProperties) {
+    if let Some(timestamp) = properties.timestamp() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let latency = now.as_secs().saturating_sub(*timestamp);
+        QUEUE_LATENCY.with_label_values(&[queue]).observe(latency as f64);
+    }
+}
+
+fn health_response(report: Report) -> Result<Response<Body>, hyper::http::Error> {
+    let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let mut body = if report.lines.is_empty() { "ok".to_owned() } else { report.lines.join("\n") };
+    body.push('\n');
+    Response::builder().status(status).body(Body::from(body))
+}
+
+async fn handle(request: Request<Body>, health: Health) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => health_response(health.liveness()),
+        (&Method::GET, "/readyz") => health_response(health.readiness().await),
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(()) => Response::builder()
+                    .header(header::CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(buffer)),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(e.to_string())),
+            }
+        },
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+
+    Ok(response.unwrap())
+}
+
+/// Serves `/metrics` in the Prometheus text format on `addr`, next to the liveness and
+/// readiness endpoints `/healthz` and `/readyz`.
+pub async fn serve(addr: SocketAddr, health: Health) -> Result<(), Error> {
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(request, health.clone())))
+        }
+    });
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
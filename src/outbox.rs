@@ -0,0 +1,80 @@
+//! Outbox for task submission.
+//!
+//! A task is inserted together with a [`TaskOutbox`] entry in the same document, so the write is
+//! atomic without multi-document transactions. The entry is relayed to the queue right after the
+//! insert, and entries whose publish failed or never happened are relayed again on startup. A task
+//! may be published twice if the bot stops between the broker's confirm and marking the entry
+//! sent, which is preferred over losing it.
+
+use crate::{Error, amqp::{self, QueueNames}, metrics, schemas::{Stage, TaskOutbox, VideoStylizerTaskCreation, VideoStylizerTaskInDB}, telemetry};
+use futures::TryStreamExt;
+use mongodb::{Collection, bson::{self, doc, oid::ObjectId, DateTime, Document}};
+use tracing::Instrument;
+
+/// Outbox entry for a task submitted from the current span.
+pub fn entry() -> TaskOutbox {
+    TaskOutbox {
+        trace_context: telemetry::current_context(),
+        sent_at: None,
+    }
+}
+
+/// Publishes a task to the queue of its first stage and marks its outbox entry sent once the
+/// broker confirmed it. Requires `channel` to be in confirm mode.
+#[tracing::instrument(skip(task_collection, channel, queues, task))]
+pub async fn relay(
+    task_collection: &Collection<VideoStylizerTaskInDB>,
+    channel: &lapin::Channel,
+    queues: &QueueNames,
+    task_id: &str,
+    task: VideoStylizerTaskCreation,
+) -> Result<(), Error> {
+    let id = ObjectId::parse_str(task_id)?;
+    let stage = task.stages.first().copied().unwrap_or(Stage::Stylize);
+    let queue = queues.pending(stage);
+    let submitted = metrics::TASKS_SUBMITTED
+        .with_label_values(&[metrics::style_label(task.style.as_deref()), &metrics::guild_label(task.guild_id)]);
+
+    let properties = amqp::task_properties(task_id);
+    let payload = serde_json::to_vec(&task.with_task_id(task_id.to_owned())).unwrap();
+
+    amqp::publish(channel, queue, &payload, properties).await?;
+
+    task_collection.update_one(
+        doc! {"_id": id},
+        doc! {"$set": {"outbox.sent_at": DateTime::now()}},
+        None,
+    ).await?;
+
+    submitted.inc();
+    tracing::info!(queue, "Published task");
+
+    Ok(())
+}
+
+/// Relays every outbox entry that was never sent, returning how many were published.
+pub async fn relay_pending(
+    task_collection: &Collection<VideoStylizerTaskInDB>,
+    channel: &lapin::Channel,
+    queues: &QueueNames,
+) -> Result<usize, Error> {
+    let mut cursor = task_collection.clone_with_type::<Document>().find(
+        doc! {"outbox": {"$type": "object"}, "outbox.sent_at": null},
+        None,
+    ).await?;
+
+    let mut relayed = 0;
+    while let Some(document) = cursor.try_next().await? {
+        let task_id = document.get_object_id("_id")?.to_hex();
+        let task: VideoStylizerTaskInDB = bson::from_document(document)?;
+
+        let span = tracing::info_span!("relay_pending_task", task_id = %task_id);
+        if let Some(outbox) = &task.outbox {
+            telemetry::set_parent_from_context(&span, &outbox.trace_context);
+        }
+        relay(task_collection, channel, queues, &task_id, task.into()).instrument(span).await?;
+        relayed += 1;
+    }
+
+    Ok(relayed)
+}
@@ -1,16 +1,109 @@
+use crate::error_code::ErrorCode;
 use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Mp4,
+    WebM,
+    Gif,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Mp4 => "mp4",
+            OutputFormat::WebM => "webm",
+            OutputFormat::Gif => "gif",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Stylize,
+    Upscale,
+    Interpolate,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Stylize => "stylize",
+            Stage::Upscale => "upscale",
+            Stage::Interpolate => "interpolate",
+        }
+    }
+
+    pub fn queue(&self) -> &'static str {
+        match self {
+            Stage::Stylize => "pendingVideoStylizerTasks",
+            Stage::Upscale => "pendingVideoUpscaleTasks",
+            Stage::Interpolate => "pendingVideoInterpolationTasks",
+        }
+    }
+}
+
+fn default_stages() -> Vec<Stage> {
+    vec![Stage::Stylize]
+}
+
+/// Output of one finished pipeline stage.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StageArtifact {
+    pub stage: Stage,
+    pub output_path: String,
+    pub completed_at: DateTime,
+}
+
+/// Where the audio of a result comes from: the source video, cut to the part that was stylized.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AudioSource {
+    pub url: String,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+/// Why a task failed: a code from the catalogue for users and the internal detail for admins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskError {
+    pub code: ErrorCode,
+    pub detail: String,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoStylizerTaskCreation {
     pub user_id: u64,
     pub channel_id: u64,
+    /// Guild the task was submitted in, or `None` in DMs.
+    pub guild_id: Option<u64>,
     pub src_video_url: String,
     pub video_prompt: Option<String>,
     pub style_prompt: String,
+    /// Name of the preset the style prompt came from, `None` for custom prompts.
+    #[serde(default)]
+    pub style: Option<String>,
     pub negative_prompt: Option<String>,
     pub max_keyframes: Option<u64>,
     pub seed: u64,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub fps: Option<f64>,
+    pub preview: bool,
+    pub group_id: Option<String>,
+    pub keep_audio: bool,
+    /// Set once the stylize stage ran, or taken over from the task a follow-up started from.
+    #[serde(default)]
+    pub audio: Option<AudioSource>,
+    pub output_format: OutputFormat,
+    pub stages: Vec<Stage>,
+    pub locale: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,88 +111,279 @@ pub struct VideoStylizerTaskInQueue {
     pub task_id: String,
     pub user_id: u64,
     pub channel_id: u64,
+    pub guild_id: Option<u64>,
     pub src_video_url: String,
     pub video_prompt: Option<String>,
     pub style_prompt: String,
+    /// Name of the preset the style prompt came from, `None` for custom prompts.
+    #[serde(default)]
+    pub style: Option<String>,
     pub negative_prompt: Option<String>,
     pub max_keyframes: Option<u64>,
     pub seed: u64,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub fps: Option<f64>,
+    #[serde(default)]
+    pub preview: bool,
+    pub group_id: Option<String>,
+    #[serde(default)]
+    pub keep_audio: bool,
+    /// Set once the stylize stage ran, or taken over from the task a follow-up started from.
+    #[serde(default)]
+    pub audio: Option<AudioSource>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default = "default_stages")]
+    pub stages: Vec<Stage>,
+    pub locale: Option<String>,
     pub status: String,
     pub result: Option<String>,
+    pub error: Option<TaskError>,
+    #[serde(default)]
+    pub preview_frames: Vec<String>,
+    #[serde(default)]
+    pub artifacts: Vec<StageArtifact>,
+    /// Still of the source video, rendered by the worker next to the result for embeds.
+    #[serde(default)]
+    pub source_thumbnail: Option<String>,
+    /// Still of the result video, rendered by the worker for embeds.
+    #[serde(default)]
+    pub result_thumbnail: Option<String>,
+    /// Number of times the current stage was retried after a temporary backend failure.
+    #[serde(default)]
+    pub attempts: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VideoStylizerTaskInDB {
     pub user_id: u64,
     pub channel_id: u64,
+    pub guild_id: Option<u64>,
     pub src_video_url: String,
     pub video_prompt: Option<String>,
     pub style_prompt: String,
+    /// Name of the preset the style prompt came from, `None` for custom prompts.
+    #[serde(default)]
+    pub style: Option<String>,
     pub negative_prompt: Option<String>,
     pub max_keyframes: Option<u64>,
     pub seed: u64,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub fps: Option<f64>,
+    #[serde(default)]
+    pub preview: bool,
+    pub group_id: Option<String>,
+    #[serde(default)]
+    pub keep_audio: bool,
+    /// Set once the stylize stage ran, or taken over from the task a follow-up started from.
+    #[serde(default)]
+    pub audio: Option<AudioSource>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default = "default_stages")]
+    pub stages: Vec<Stage>,
+    pub locale: Option<String>,
     pub status: String,
     pub result: Option<String>,
+    pub error: Option<TaskError>,
+    #[serde(default)]
+    pub preview_frames: Vec<String>,
+    #[serde(default)]
+    pub artifacts: Vec<StageArtifact>,
+    /// Still of the source video, rendered by the worker next to the result for embeds.
+    #[serde(default)]
+    pub source_thumbnail: Option<String>,
+    /// Still of the result video, rendered by the worker for embeds.
+    #[serde(default)]
+    pub result_thumbnail: Option<String>,
+    /// Publish of the task to its queue, written in the same document so it can't get lost.
+    #[serde(default)]
+    pub outbox: Option<TaskOutbox>,
+    /// When the user was notified of the result, claimed together with the final status.
+    #[serde(default)]
+    pub notified_at: Option<DateTime>,
+    /// Message the result or failure was posted in.
+    #[serde(default)]
+    pub notification_message_id: Option<u64>,
+    /// Reply saying the task was created, which the result replies to.
+    #[serde(default)]
+    pub placeholder_message_id: Option<u64>,
+    /// Tasks started from the buttons on this task's result, keyed by button, so each button
+    /// only ever starts one.
+    #[serde(default)]
+    pub follow_ups: HashMap<String, String>,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
 
+/// Outbox entry of a task, relayed to the queue by [`crate::outbox`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskOutbox {
+    /// Trace context of the submitting command, so a retried publish continues its trace.
+    #[serde(default)]
+    pub trace_context: HashMap<String, String>,
+    pub sent_at: Option<DateTime>,
+}
+
 impl VideoStylizerTaskCreation {
     pub fn with_task_id(self, task_id: String) -> VideoStylizerTaskInQueue {
         VideoStylizerTaskInQueue {
             task_id,
             user_id: self.user_id,
             channel_id: self.channel_id,
+            guild_id: self.guild_id,
             src_video_url: self.src_video_url,
             video_prompt: self.video_prompt,
             style_prompt: self.style_prompt,
+            style: self.style,
             negative_prompt: self.negative_prompt,
             max_keyframes: self.max_keyframes,
             seed: self.seed,
+            start: self.start,
+            end: self.end,
+            max_width: self.max_width,
+            max_height: self.max_height,
+            fps: self.fps,
+            preview: self.preview,
+            group_id: self.group_id,
+            keep_audio: self.keep_audio,
+            audio: self.audio,
+            output_format: self.output_format,
+            stages: self.stages,
+            locale: self.locale,
             status: "pending".to_owned(),
             result: None,
+            error: None,
+            preview_frames: Vec::new(),
+            artifacts: Vec::new(),
+            source_thumbnail: None,
+            result_thumbnail: None,
+            attempts: 0,
         }
     }
 }
 
-impl Into<VideoStylizerTaskInDB> for VideoStylizerTaskCreation {
-    fn into(self) -> VideoStylizerTaskInDB {
+impl From<VideoStylizerTaskCreation> for VideoStylizerTaskInDB {
+    fn from(task: VideoStylizerTaskCreation) -> Self {
         VideoStylizerTaskInDB {
-            user_id: self.user_id,
-            channel_id: self.channel_id,
-            src_video_url: self.src_video_url,
-            video_prompt: self.video_prompt,
-            style_prompt: self.style_prompt,
-            negative_prompt: self.negative_prompt,
-            max_keyframes: self.max_keyframes,
-            seed: self.seed,
+            user_id: task.user_id,
+            channel_id: task.channel_id,
+            guild_id: task.guild_id,
+            src_video_url: task.src_video_url,
+            video_prompt: task.video_prompt,
+            style_prompt: task.style_prompt,
+            style: task.style,
+            negative_prompt: task.negative_prompt,
+            max_keyframes: task.max_keyframes,
+            seed: task.seed,
+            start: task.start,
+            end: task.end,
+            max_width: task.max_width,
+            max_height: task.max_height,
+            fps: task.fps,
+            preview: task.preview,
+            group_id: task.group_id,
+            keep_audio: task.keep_audio,
+            audio: task.audio,
+            output_format: task.output_format,
+            stages: task.stages,
+            locale: task.locale,
             status: "pending".to_owned(),
             result: None,
+            error: None,
+            preview_frames: Vec::new(),
+            artifacts: Vec::new(),
+            source_thumbnail: None,
+            result_thumbnail: None,
+            outbox: None,
+            notified_at: None,
+            notification_message_id: None,
+            placeholder_message_id: None,
+            follow_ups: HashMap::new(),
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
         }
     }
 }
 
-impl Into<VideoStylizerTaskInDB> for VideoStylizerTaskInQueue {
-    fn into(self) -> VideoStylizerTaskInDB {
+impl From<VideoStylizerTaskInQueue> for VideoStylizerTaskInDB {
+    fn from(task: VideoStylizerTaskInQueue) -> Self {
         VideoStylizerTaskInDB {
-            user_id: self.user_id,
-            channel_id: self.channel_id,
-            src_video_url: self.src_video_url,
-            video_prompt: self.video_prompt,
-            style_prompt: self.style_prompt,
-            negative_prompt: self.negative_prompt,
-            max_keyframes: self.max_keyframes,
-            seed: self.seed,
-            status: self.status,
-            result: self.result,
+            user_id: task.user_id,
+            channel_id: task.channel_id,
+            guild_id: task.guild_id,
+            src_video_url: task.src_video_url,
+            video_prompt: task.video_prompt,
+            style_prompt: task.style_prompt,
+            style: task.style,
+            negative_prompt: task.negative_prompt,
+            max_keyframes: task.max_keyframes,
+            seed: task.seed,
+            start: task.start,
+            end: task.end,
+            max_width: task.max_width,
+            max_height: task.max_height,
+            fps: task.fps,
+            preview: task.preview,
+            group_id: task.group_id,
+            keep_audio: task.keep_audio,
+            audio: task.audio,
+            output_format: task.output_format,
+            stages: task.stages,
+            locale: task.locale,
+            status: task.status,
+            result: task.result,
+            error: task.error,
+            preview_frames: task.preview_frames,
+            artifacts: task.artifacts,
+            source_thumbnail: task.source_thumbnail,
+            result_thumbnail: task.result_thumbnail,
+            outbox: None,
+            notified_at: None,
+            notification_message_id: None,
+            placeholder_message_id: None,
+            follow_ups: HashMap::new(),
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
         }
     }
 }
 
+impl From<VideoStylizerTaskInDB> for VideoStylizerTaskCreation {
+    fn from(task: VideoStylizerTaskInDB) -> Self {
+        VideoStylizerTaskCreation {
+            user_id: task.user_id,
+            channel_id: task.channel_id,
+            guild_id: task.guild_id,
+            src_video_url: task.src_video_url,
+            video_prompt: task.video_prompt,
+            style_prompt: task.style_prompt,
+            style: task.style,
+            negative_prompt: task.negative_prompt,
+            max_keyframes: task.max_keyframes,
+            seed: task.seed,
+            start: task.start,
+            end: task.end,
+            max_width: task.max_width,
+            max_height: task.max_height,
+            fps: task.fps,
+            preview: task.preview,
+            group_id: task.group_id,
+            keep_audio: task.keep_audio,
+            audio: task.audio,
+            output_format: task.output_format,
+            stages: task.stages,
+            locale: task.locale,
+        }
+    }
+}
+
 impl VideoStylizerTaskInQueue {
     pub fn with_result(self, status: String, result: String) -> VideoStylizerTaskInQueue {
         VideoStylizerTaskInQueue {
@@ -108,4 +392,48 @@ impl VideoStylizerTaskInQueue {
             ..self
         }
     }
+
+    pub fn with_error(self, code: ErrorCode, detail: String) -> VideoStylizerTaskInQueue {
+        VideoStylizerTaskInQueue {
+            status: "failed".to_owned(),
+            result: None,
+            error: Some(TaskError { code, detail }),
+            ..self
+        }
+    }
+
+    /// The stage that still has to run, or `None` once every stage has produced an artifact.
+    pub fn next_stage(&self) -> Option<Stage> {
+        self.stages.get(self.artifacts.len()).copied()
+    }
+
+    /// Input of the next stage: the previous stage's artifact, or the source video.
+    pub fn stage_input(&self) -> &str {
+        self.artifacts.last().map_or(&self.src_video_url, |artifact| &artifact.output_path)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VideoStylizerGroupInDB {
+    pub user_id: u64,
+    pub channel_id: u64,
+    pub kind: String,
+    pub task_ids: Vec<String>,
+    pub grid: bool,
+    pub status: String,
+    /// Reply saying the group was created, which the results reply to.
+    #[serde(default)]
+    pub placeholder_message_id: Option<u64>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+/// Settings a user chose for themselves with the settings commands.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserPreferencesInDB {
+    pub user_id: u64,
+    /// Also send results to the user's DMs, not only to the channel the command was used in.
+    #[serde(default)]
+    pub notify_by_dm: bool,
+    pub updated_at: DateTime,
 }
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch};
+
+/// Resolves on the first `SIGTERM` or `SIGINT`.
+pub async fn terminate_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+}
+
+/// Counts work that has to finish before the process exits, such as a task submission that
+/// has been written to the database but not yet published.
+#[derive(Clone)]
+pub struct InFlight(Arc<watch::Sender<usize>>);
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight(Arc::new(watch::channel(0).0))
+    }
+}
+
+impl InFlight {
+    /// Marks work as started until the returned guard is dropped.
+    pub fn enter(&self) -> InFlightGuard {
+        self.0.send_modify(|count| *count += 1);
+        InFlightGuard(self.0.clone())
+    }
+
+    pub async fn wait_idle(&self) {
+        let mut count = self.0.subscribe();
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+}
+
+pub struct InFlightGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
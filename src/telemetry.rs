@@ -0,0 +1,136 @@
+use crate::Error;
+use lapin::{BasicProperties, types::{AMQPValue, FieldTable}};
+use opentelemetry::{global, propagation::{Extractor, Injector}, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// AMQP header carrying the ID of the task in a message, for correlating logs without a collector.
+pub const TASK_ID_HEADER: &str = "x-task-id";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Logging settings shared by the bot and the worker. Verbosity is set with `RUST_LOG`.
+#[derive(clap::Args, Clone, Debug)]
+pub struct LogConfig {
+    #[clap(value_enum, default_value = "text", long, env)]
+    pub log_format: LogFormat,
+    /// OTLP/gRPC endpoint to export traces to, e.g. `http://localhost:4317`.
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Flushes spans that have not been exported yet when dropped at the end of `main`.
+pub struct TelemetryGuard(Option<TracerProvider>);
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber, exporting spans as `service_name` when an OTLP endpoint is set.
+pub fn init(config: &LogConfig, service_name: &'static str) -> Result<TelemetryGuard, Error> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let fmt_layer = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_span_list(true).boxed(),
+    };
+
+    let provider = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?;
+            Some(TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+                .build())
+        },
+        None => None,
+    };
+    let otel_layer = provider.as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .with(filter)
+        .try_init()?;
+
+    Ok(TelemetryGuard(provider))
+}
+
+struct HeaderInjector<'a>(&'a mut FieldTable);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.into(), AMQPValue::LongString(value.into()));
+    }
+}
+
+struct HeaderExtractor<'a>(&'a FieldTable);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.inner().get(key) {
+            Some(AMQPValue::LongString(value)) => std::str::from_utf8(value.as_bytes()).ok(),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.inner().keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Headers carrying `task_id` and the trace context of the current span to the consumer.
+pub fn task_headers(task_id: &str) -> FieldTable {
+    let mut headers = FieldTable::default();
+    headers.insert(TASK_ID_HEADER.into(), AMQPValue::LongString(task_id.into()));
+
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+
+    headers
+}
+
+/// Trace context of the current span, for storing alongside work that is resumed later.
+pub fn current_context() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    carrier
+}
+
+/// Continues a trace stored with [`current_context`] in `span`.
+pub fn set_parent_from_context(span: &tracing::Span, carrier: &HashMap<String, String>) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    span.set_parent(context);
+}
+
+/// Continues the trace of the message's publisher in `span`.
+pub fn set_parent_from_headers(span: &tracing::Span, properties: &BasicProperties) {
+    if let Some(headers) = properties.headers() {
+        let context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(context);
+    }
+}